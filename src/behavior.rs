@@ -1,2 +1,312 @@
+use std::fs::File;
+use std::io::BufReader;
+use crate::core::node::NodeParameters;
+use crate::core::node_graph::register_node_recipe;
+use crate::core::wav::read_wav;
+use crate::core::error::GraphError;
+use crate::behavior::basic::*;
+use crate::behavior::waveform::*;
+use crate::behavior::oscillator::*;
+use crate::behavior::wavetable::*;
+use crate::behavior::noise::*;
+use crate::behavior::envelope::*;
+
 pub mod basic;
-pub mod waveform;
\ No newline at end of file
+pub mod waveform;
+pub mod oscillator;
+pub mod wavetable;
+pub mod noise;
+pub mod envelope;
+
+// The most inputs a sum or product node can have
+const MAX_NUM_INS: usize = 64;
+
+// Sizes and counts have a maximum, so a single message can't make the graph allocate more than it can
+pub(crate) fn usize_parameter(parameters: &mut NodeParameters, name: &str, default: usize, max: usize) -> Result<usize, GraphError> {
+	match parameters.get(name) {
+		None => {
+			parameters.insert(name.to_string(), serde_json::json!(default));
+			Ok(default)
+		},
+		Some(value) => match value.as_u64() {
+			Some(number) if number <= max as u64 => Ok(number as usize),
+			_ => Err(GraphError::InvalidParameter { name: name.to_string(), reason: format!("expected a positive integer up to {}, got {}", max, value) })
+		}
+	}
+}
+
+fn float_list_parameter(parameters: &mut NodeParameters, name: &str, default: Vec<f32>) -> Result<Vec<f32>, GraphError> {
+	match parameters.get(name) {
+		None => {
+			parameters.insert(name.to_string(), serde_json::json!(default));
+			Ok(default)
+		},
+		Some(value) => {
+			let list: Option<Vec<f32>> = value.as_array().and_then(|items| {
+				items.iter().map(|item| item.as_f64().map(|number| number as f32)).collect()
+			});
+
+			match list {
+				Some(list) if !list.is_empty() => Ok(list),
+				_ => Err(GraphError::InvalidParameter { name: name.to_string(), reason: format!("expected a non-empty list of numbers, got {}", value) })
+			}
+		}
+	}
+}
+
+// Noise nodes without a seed get a new one every time they're created, which goes into their parameters
+// so a saved patch plays the same noise again
+fn seed_parameter(parameters: &mut NodeParameters) -> Result<u64, GraphError> {
+	match parameters.get("seed") {
+		None => {
+			let seed = random_seed();
+			parameters.insert(String::from("seed"), serde_json::json!(seed));
+			Ok(seed)
+		},
+		Some(value) => value.as_u64().ok_or_else(|| GraphError::InvalidParameter { name: String::from("seed"), reason: format!("expected a positive integer, got {}", value) })
+	}
+}
+
+fn noise_color_parameter(parameters: &mut NodeParameters) -> Result<NoiseColor, GraphError> {
+	match parameters.get("color") {
+		None => {
+			parameters.insert(String::from("color"), serde_json::json!("white"));
+			Ok(NoiseColor::White)
+		},
+		Some(value) => value.as_str().and_then(NoiseColor::from_name).ok_or_else(|| {
+			GraphError::InvalidParameter { name: String::from("color"), reason: format!("expected white, pink or brown, got {}", value) }
+		})
+	}
+}
+
+// Tables are cut out of a WAV file this long by default, which is what most wavetable synths save
+const DEFAULT_WAVETABLE_SIZE: usize = 2048;
+const MAX_WAVETABLE_SIZE: usize = 1 << 16;
+
+// The single cycle tables of a wavetable node: either a list of lists of samples in "tables",
+// or a WAV file in "file" holding tables of "table_size" frames back to back, of which only the first channel is used
+fn wavetables_parameter(parameters: &mut NodeParameters) -> Result<Vec<Vec<f32>>, GraphError> {
+	let invalid = |name: &str, reason: String| GraphError::InvalidParameter { name: name.to_string(), reason };
+
+	let tables = match parameters.get("file") {
+		Some(path) => {
+			let path = path.as_str().map(String::from).ok_or_else(|| invalid("file", format!("expected the path of a WAV file, got {}", path)))?;
+
+			let file = File::open(&path).map_err(|e| invalid("file", format!("{}: {}", path, e)))?;
+			let wav = read_wav(BufReader::new(file)).map_err(|e| invalid("file", format!("{}: {}", path, e)))?;
+			let samples: Vec<f32> = wav.samples.iter().step_by(usize::from(wav.channels.max(1))).copied().collect();
+
+			let table_size = usize_parameter(parameters, "table_size", DEFAULT_WAVETABLE_SIZE, MAX_WAVETABLE_SIZE)?;
+
+			if table_size < 2 {
+				return Err(invalid("table_size", String::from("a table needs at least 2 samples")));
+			}
+
+			// a file shorter than a table is a single table
+			if samples.len() <= table_size {
+				vec![samples]
+			} else if samples.len().is_multiple_of(table_size) {
+				samples.chunks(table_size).map(|table| table.to_vec()).collect()
+			} else {
+				return Err(invalid("table_size", format!("{} has {} frames, which isn't a whole number of tables of {} frames", path, samples.len(), table_size)));
+			}
+		},
+		None => {
+			let value = parameters.get("tables").ok_or_else(|| invalid("tables", String::from("expected either tables or a file")))?;
+
+			let tables: Option<Vec<Vec<f32>>> = value.as_array().and_then(|tables| tables.iter().map(|table| {
+				table.as_array().and_then(|items| items.iter().map(|item| item.as_f64().map(|number| number as f32)).collect())
+			}).collect());
+
+			tables.ok_or_else(|| invalid("tables", format!("expected a list of lists of numbers, got {}", value)))?
+		}
+	};
+
+	if tables.is_empty() || tables.iter().any(|table| table.len() < 2) {
+		return Err(invalid("tables", String::from("expected at least one table, of at least 2 samples each")));
+	}
+
+	Ok(tables)
+}
+
+// The breakpoints of an envelope node, as [duration in seconds, level] pairs in "points"
+fn breakpoints_parameter(parameters: &NodeParameters) -> Result<Vec<Breakpoint>, GraphError> {
+	let value = parameters.get("points").ok_or_else(|| GraphError::InvalidParameter { name: String::from("points"), reason: String::from("expected a list of [duration, level] pairs") })?;
+
+	let points: Option<Vec<Breakpoint>> = value.as_array().and_then(|points| points.iter().map(|point| {
+		match point.as_array().map(|pair| pair.as_slice()) {
+			Some([duration, level]) => match (duration.as_f64(), level.as_f64()) {
+				(Some(duration), Some(level)) if duration >= 0.0 => Some(Breakpoint { duration: duration as f32, level: level as f32 }),
+				_ => None
+			},
+			_ => None
+		}
+	}).collect());
+
+	match points {
+		Some(points) if !points.is_empty() => Ok(points),
+		_ => Err(GraphError::InvalidParameter { name: String::from("points"), reason: format!("expected a non-empty list of [duration, level] pairs, with durations of 0 or more, got {}", value) })
+	}
+}
+
+// The segments that repeat while the gate of an envelope node is held, as the first and last of them in "loop"
+fn loop_parameter(parameters: &NodeParameters, points: &[Breakpoint]) -> Result<Option<(usize, usize)>, GraphError> {
+	let invalid = |reason: String| GraphError::InvalidParameter { name: String::from("loop"), reason };
+
+	let value = match parameters.get("loop") {
+		None => return Ok(None),
+		Some(value) => value
+	};
+
+	let bounds: Option<Vec<usize>> = value.as_array().and_then(|items| items.iter().map(|item| item.as_u64().map(|idx| idx as usize)).collect());
+
+	match bounds.as_deref() {
+		Some(&[first, last]) if first <= last && last < points.len() => {
+			// a loop that takes no time would go round forever without moving
+			if points[first..=last].iter().all(|point| point.duration == 0.0) {
+				return Err(invalid(String::from("the segments of the loop can't all be 0 seconds long")));
+			}
+
+			Ok(Some((first, last)))
+		},
+		_ => Err(invalid(format!("expected the first and last segment of the loop, out of {}, got {}", points.len(), value)))
+	}
+}
+
+// Registers a recipe for every behavior that can be created from parameters alone,
+// so clients and patches can add them by type name
+pub fn register_node_recipes(){
+	register_node_recipe("SumNode", Box::new(|parameters| Ok(Box::new(SumNode::new(usize_parameter(parameters, "num_ins", 2, MAX_NUM_INS)?)))));
+	register_node_recipe("ProductNode", Box::new(|parameters| Ok(Box::new(ProductNode::new(usize_parameter(parameters, "num_ins", 2, MAX_NUM_INS)?)))));
+	register_node_recipe("WaveformNode", Box::new(|parameters| Ok(Box::new(WaveformNode::new(float_list_parameter(parameters, "waveform", vec![0.0])?)))));
+	register_node_recipe("SinNode", Box::new(|_| Ok(Box::new(SinNode::new()))));
+	register_node_recipe("SawNode", Box::new(|_| Ok(Box::new(OscillatorNode::new(OscillatorShape::Saw)))));
+	register_node_recipe("PulseNode", Box::new(|_| Ok(Box::new(OscillatorNode::new(OscillatorShape::Pulse)))));
+	register_node_recipe("TriangleNode", Box::new(|_| Ok(Box::new(OscillatorNode::new(OscillatorShape::Triangle)))));
+	register_node_recipe("WavetableNode", Box::new(|parameters| Ok(Box::new(WavetableNode::new(wavetables_parameter(parameters)?)))));
+	register_node_recipe("NoiseNode", Box::new(|parameters| Ok(Box::new(NoiseNode::new(noise_color_parameter(parameters)?, seed_parameter(parameters)?)))));
+	register_node_recipe("SampleAndHoldNode", Box::new(|_| Ok(Box::new(SampleAndHoldNode::new()))));
+	register_node_recipe("AdsrNode", Box::new(|_| Ok(Box::new(AdsrNode::new()))));
+	register_node_recipe("EnvelopeNode", Box::new(|parameters| {
+		let points = breakpoints_parameter(parameters)?;
+		let loop_segments = loop_parameter(parameters, &points)?;
+
+		Ok(Box::new(EnvelopeNode::new(points, loop_segments)))
+	}));
+}
+
+// Tests share the cookbook, which takes every recipe once
+#[cfg(test)]
+pub(crate) fn register_node_recipes_once(){
+	static REGISTERED: std::sync::Once = std::sync::Once::new();
+
+	REGISTERED.call_once(register_node_recipes);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::core::node::{NodeBehavior, NodeIn, NodeOut, ProcessContext};
+
+	const SAMPLE_RATE: f32 = 44100.0;
+	const BLOCK_SIZE: usize = 256;
+
+	// The power that isn't close to a harmonic of freq, relative to the power that is, in dB.
+	// Harmonics above nyquist fold back in between the others, so this is the aliasing.
+	pub(crate) fn alias_ratio(signal: &[f32], freq: f32) -> f64 {
+		const LEN: usize = 8192;
+		// the main lobe of the Blackman window is 3 bins wide on either side
+		const HARMONIC_BINS: f64 = 4.0;
+
+		let mut re: Vec<f64> = signal[1000..1000 + LEN].iter().enumerate().map(|(i, sample)| {
+			let x = std::f64::consts::TAU * i as f64 / (LEN - 1) as f64;
+			f64::from(*sample) * (0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos())
+		}).collect();
+		let mut im = vec![0.0; LEN];
+
+		fft(&mut re, &mut im, false);
+
+		let bin_freq = f64::from(SAMPLE_RATE) / LEN as f64;
+		let (mut harmonic, mut alias) = (0.0, 0.0);
+
+		for bin in 1..LEN / 2 {
+			let power = re[bin] * re[bin] + im[bin] * im[bin];
+			let harmonics = bin as f64 * bin_freq / f64::from(freq);
+
+			if harmonics.round() >= 1.0 && (harmonics - harmonics.round()).abs() * f64::from(freq) < HARMONIC_BINS * bin_freq {
+				harmonic += power;
+			} else {
+				alias += power;
+			}
+		}
+
+		10.0 * (alias / harmonic).log10()
+	}
+
+	// The largest change from one sample to the next
+	pub(crate) fn max_step(signal: &[f32]) -> f32 {
+		signal.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max)
+	}
+
+	fn oscillators() -> Vec<Box<dyn NodeBehavior>> {
+		let sine: Vec<f32> = (0..256).map(|i| (std::f32::consts::TAU * i as f32 / 256.0).sin()).collect();
+		let saw: Vec<f32> = (0..256).map(|i| i as f32 / 128.0 - 1.0).collect();
+
+		vec![
+			Box::new(SinNode::new()),
+			Box::new(OscillatorNode::new(OscillatorShape::Saw)),
+			Box::new(OscillatorNode::new(OscillatorShape::Pulse)),
+			Box::new(OscillatorNode::new(OscillatorShape::Triangle)),
+			Box::new(WavetableNode::new(vec![sine, saw]))
+		]
+	}
+
+	// Runs an oscillator at freq for len samples, counting the cycles of its phase output and checking where it ends.
+	// Every oscillator takes its frequency on its first input, and puts out its phase on its second output.
+	fn assert_stays_on_frequency(node: &mut dyn NodeBehavior, freq: f32, len: usize) {
+		node.prepare(&ProcessContext::new(SAMPLE_RATE, BLOCK_SIZE));
+
+		let info = node.get_info();
+		let mut ins: Vec<NodeIn> = (0..info.num_ins).map(|_| NodeIn::new(Vec::new(), BLOCK_SIZE)).collect();
+		ins[0].buffer.fill(freq);
+		let mut outs: Vec<NodeOut> = (0..info.num_outs).map(|_| NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }).collect();
+
+		let mut num_cycles: u64 = 0;
+		let mut last_phase = 0.0;
+
+		for _ in 0..len / BLOCK_SIZE {
+			node.update(&ins, &mut outs, BLOCK_SIZE);
+
+			for phase in outs[1].buffer.iter() {
+				if *phase < last_phase {
+					num_cycles += 1;
+				}
+				last_phase = *phase;
+			}
+		}
+
+		// the phase output of sample n is where the cycle is after n steps
+		let len = len / BLOCK_SIZE * BLOCK_SIZE;
+		let cycles = (len - 1) as f64 * f64::from(freq) / f64::from(SAMPLE_RATE);
+
+		assert_eq!(num_cycles, cycles.floor() as u64, "{} at {} Hz", info.type_name, freq);
+		assert!((f64::from(last_phase) - cycles.fract()).abs() < 1e-6, "{} at {} Hz ends at phase {}", info.type_name, freq, last_phase);
+	}
+
+	#[test]
+	fn oscillators_stay_on_frequency() {
+		// almost four minutes, where a phase in single precision would be off by many cycles
+		for mut node in oscillators() {
+			assert_stays_on_frequency(node.as_mut(), 1234.5, 10_000_000);
+		}
+	}
+
+	// Two hours, run with cargo test --release -- --ignored
+	#[test]
+	#[ignore]
+	fn oscillators_stay_on_frequency_for_hours() {
+		for mut node in oscillators() {
+			assert_stays_on_frequency(node.as_mut(), 1234.5, 2 * 60 * 60 * 44100);
+		}
+	}
+}
\ No newline at end of file
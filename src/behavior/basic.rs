@@ -1,5 +1,7 @@
-use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, BUFFER_SIZE};
-use crate::core::heaped::Heaped;
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut};
+use crate::core::input::SharedInputBuffer;
+use crate::core::renderer::SharedOutputBuffer;
+use crate::core::audio::mix_frame;
 extern crate ringbuf;
 
 pub struct SumNode {
@@ -9,7 +11,7 @@ pub struct SumNode {
 impl SumNode {
 	pub fn new(num_ins: usize) -> SumNode {
 		SumNode {
-			num_ins: num_ins
+			num_ins
 		}
 	}
 }
@@ -19,19 +21,20 @@ impl NodeBehavior for SumNode {
 		NodeBehaviorInfo {
 			type_name: String::from("SumNode"),
 			num_ins: self.num_ins,
-			num_outs: 1
+			num_outs: 1,
+			parameters: Vec::new()
 		}
 	}
 
-	fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>){
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
 		let output = outputs.get_mut(0).unwrap();
 		let mut sum: f32;
 
-		for n in 0..output.buffer.len() {
+		for n in 0..num_frames {
 			sum = 0.0;
 
-			for i in 0..inputs.len() {
-				sum += inputs[i].buffer[n];
+			for inp in inputs.iter() {
+				sum += inp.buffer[n];
 			}
 
 			output.buffer[n] = sum;
@@ -46,7 +49,7 @@ pub struct ProductNode {
 impl ProductNode {
 	pub fn new(num_ins: usize) -> ProductNode {
 		ProductNode {
-			num_ins: num_ins
+			num_ins
 		}
 	}
 }
@@ -56,19 +59,20 @@ impl NodeBehavior for ProductNode {
 		NodeBehaviorInfo {
 			type_name: String::from("ProductNode"),
 			num_ins: self.num_ins,
-			num_outs: 1
+			num_outs: 1,
+			parameters: Vec::new()
 		}
 	}
 
-	fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>){
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
 		let output = outputs.get_mut(0).unwrap();
 		let mut sum: f32;
 
-		for n in 0..output.buffer.len() {
+		for n in 0..num_frames {
 			sum = 1.0;
 
-			for i in 0..inputs.len() {
-				sum *= inputs[i].buffer[n];
+			for inp in inputs.iter() {
+				sum *= inp.buffer[n];
 			}
 
 			output.buffer[n] = sum;
@@ -76,35 +80,139 @@ impl NodeBehavior for ProductNode {
 	}
 }
 
-pub struct InterleavingOutputNode {
-	heaped_out_buffer: Heaped<Vec<f32>>
+// Writes its inputs to the output buffer, interleaved with as many channels as the engine's channel layout.
+// With one input per channel the inputs are patched straight through, otherwise they are mixed onto the layout
+// like a device with that number of channels would be. Every output node adds to what's in the buffer,
+// which the renderer clears before each block, so they can all be heard.
+pub struct OutputNode {
+	out_buffer: SharedOutputBuffer,
+	num_ins: usize,
+	channels: usize,
+
+	// the inputs of one frame, to mix from, and what they come to on the layout
+	frame: Vec<f32>,
+	mixed: Vec<f32>
 }
 
-impl InterleavingOutputNode {
-	pub fn new(mut out_buffer: Heaped<Vec<f32>>) -> InterleavingOutputNode {
-		InterleavingOutputNode {
-			heaped_out_buffer: out_buffer
+impl OutputNode {
+	pub fn new(out_buffer: SharedOutputBuffer, num_ins: usize, channels: usize) -> OutputNode {
+		OutputNode {
+			out_buffer,
+			num_ins,
+			channels,
+
+			frame: vec![0.0; num_ins],
+			mixed: vec![0.0; channels]
 		}
 	}
 }
 
-impl NodeBehavior for InterleavingOutputNode {
+impl NodeBehavior for OutputNode {
 	fn get_info(&self) -> NodeBehaviorInfo {
 		NodeBehaviorInfo {
-			type_name: String::from("InterleavingOutputNode"),
-			num_ins: 2,
-			num_outs: 0
+			type_name: String::from("OutputNode"),
+			num_ins: self.num_ins,
+			num_outs: 0,
+			parameters: Vec::new()
+		}
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], _outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let mut out_buffer = self.out_buffer.lock().unwrap();
+		out_buffer.resize(num_frames * self.channels, 0.0);
+
+		for (i, out_frame) in out_buffer.chunks_mut(self.channels).enumerate() {
+			for (sample, inp) in self.frame.iter_mut().zip(inputs.iter()) {
+				*sample = inp.buffer[i];
+			}
+
+			mix_frame(&self.frame, &mut self.mixed);
+
+			for (sample, mixed) in out_frame.iter_mut().zip(self.mixed.iter()) {
+				*sample += mixed;
+			}
 		}
 	}
+}
+
+// Puts out what the audio input captured, one output per channel.
+// Every input node in a graph reads the same block.
+pub struct AudioInputNode {
+	in_buffer: SharedInputBuffer,
+	channels: usize
+}
+
+impl AudioInputNode {
+	pub fn new(in_buffer: SharedInputBuffer, channels: usize) -> AudioInputNode {
+		AudioInputNode {
+			in_buffer,
+			channels
+		}
+	}
+}
+
+impl NodeBehavior for AudioInputNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		NodeBehaviorInfo {
+			type_name: String::from("AudioInputNode"),
+			num_ins: 0,
+			num_outs: self.channels,
+			parameters: Vec::new()
+		}
+	}
+
+	fn update(&mut self, _inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let in_buffer = self.in_buffer.lock().unwrap();
+
+		// right after a block size change the input can still hold a block of the old size
+		let num_captured = num_frames.min(in_buffer.len() / self.channels);
+
+		for (channel, output) in outputs.iter_mut().enumerate() {
+			for i in 0..num_captured {
+				output.buffer[i] = in_buffer[i*self.channels + channel];
+			}
+
+			output.buffer[num_captured..num_frames].fill(0.0);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::behavior::waveform::WaveformNode;
+	use crate::core::node::ProcessContext;
+	use crate::core::node_graph::NodeGraph;
+	use crate::core::renderer::new_shared_output_buffer;
+
+	#[test]
+	fn output_nodes_add_up() {
+		let (mut graph, mut renderer) = NodeGraph::new(ProcessContext::new(44100.0, 64));
+		let output_buffer = new_shared_output_buffer(2);
+
+		let left = graph.add_node("left", Box::new(WaveformNode::new(vec![0.25])));
+		let right = graph.add_node("right", Box::new(WaveformNode::new(vec![-0.5])));
+		let mono = graph.add_node("mono", Box::new(WaveformNode::new(vec![0.125])));
+
+		let stereo_output = graph.add_node("stereo_output", Box::new(OutputNode::new(output_buffer.clone(), 2, 2)));
+		let mono_output = graph.add_node("mono_output", Box::new(OutputNode::new(output_buffer.clone(), 1, 2)));
+
+		graph.connect(left, 0, stereo_output, 0).unwrap();
+		graph.connect(right, 0, stereo_output, 1).unwrap();
+		graph.connect(mono, 0, mono_output, 0).unwrap();
+		graph.commit().unwrap();
+
+		renderer.set_output_buffer(output_buffer.clone(), 2);
+
+		// twice, so the second block shows the first one was cleared rather than added to
+		for _ in 0..2 {
+			renderer.update();
 
-	fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>){
-		let left = inputs.get(0).unwrap();
-		let right = inputs.get(1).unwrap();
+			let samples = output_buffer.lock().unwrap();
+			assert_eq!(samples.len(), 64 * 2);
 
-		unsafe {
-			for i in 0..BUFFER_SIZE {
-				(*self.heaped_out_buffer.mut_ptr)[i*2] = left.buffer[i];
-				(*self.heaped_out_buffer.mut_ptr)[i*2 + 1] = right.buffer[i];
+			for frame in samples.chunks(2) {
+				assert_eq!(frame, [0.375, -0.375]);
 			}
 		}
 	}
@@ -0,0 +1,432 @@
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, ProcessContext, DEFAULT_SAMPLE_RATE};
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::smoothing::Smoothing;
+
+// Inputs and outputs of both envelopes. The envelope starts over where the gate goes above zero,
+// or where the retrigger does while the gate is open.
+const GATE_INPUT: usize = 0;
+const RETRIGGER_INPUT: usize = 1;
+
+const ENVELOPE_OUTPUT: usize = 0;
+// 1 for the sample where the envelope comes to an end or goes round its loop, 0 otherwise
+const END_OUTPUT: usize = 1;
+
+// added to the parameters of the same name, so they can be modulated
+const ATTACK_INPUT: usize = 2;
+const DECAY_INPUT: usize = 3;
+const SUSTAIN_INPUT: usize = 4;
+const RELEASE_INPUT: usize = 5;
+
+const ADSR_ATTACK: ParameterId = 0;
+const ADSR_DECAY: ParameterId = 1;
+const ADSR_SUSTAIN: ParameterId = 2;
+const ADSR_RELEASE: ParameterId = 3;
+const ADSR_EXPONENTIAL: ParameterId = 4;
+
+const MAX_STAGE_TIME: f64 = 60.0;
+
+// How far past their target exponential stages aim, so they get there in the given time instead of never.
+// The attack aims a bit over 1 and stays fairly straight, the others aim just below their target, like an RC circuit.
+const ATTACK_OVERSHOOT: f32 = 0.3;
+const DECAY_OVERSHOOT: f32 = 0.0001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AdsrStage {
+	Idle,
+	Attack,
+	Decay,
+	Sustain,
+	Release
+}
+
+// An attack, decay, sustain, release envelope between 0 and 1. Every stage starts from wherever the level is,
+// so a retrigger or an early release doesn't jump. Times are in seconds: the attack time is for rising from 0 to 1,
+// and the decay time for falling from 1 to the sustain level. The release takes its time from whatever level
+// the gate closed at.
+pub struct AdsrNode {
+	sample_rate: f32,
+
+	attack: f32,
+	decay: f32,
+	sustain: f32,
+	release: f32,
+	is_exponential: bool,
+
+	stage: AdsrStage,
+	level: f32,
+	// where the release started, so a linear release takes the release time from any level
+	release_from: f32,
+
+	last_gate: f32,
+	last_retrigger: f32
+}
+
+impl AdsrNode {
+	pub fn new() -> AdsrNode {
+		AdsrNode {
+			sample_rate: DEFAULT_SAMPLE_RATE,
+
+			attack: 0.01,
+			decay: 0.1,
+			sustain: 0.7,
+			release: 0.3,
+			is_exponential: false,
+
+			stage: AdsrStage::Idle,
+			level: 0.0,
+			release_from: 0.0,
+
+			last_gate: 0.0,
+			last_retrigger: 0.0
+		}
+	}
+
+	// Moves the level one sample towards target, returning whether it got there.
+	// distance is how far the stage goes in full, which sets the speed of linear stages.
+	fn step_towards(&mut self, target: f32, time: f32, distance: f32, overshoot: f32) -> bool {
+		let num_samples = time * self.sample_rate;
+		let is_rising = target > self.level;
+
+		if num_samples < 1.0 {
+			self.level = target;
+			return true;
+		}
+
+		if self.is_exponential {
+			// the coefficient that takes the full distance to the overshoot in num_samples
+			let coefficient = (-((distance + overshoot) / overshoot).ln() / num_samples).exp();
+			let aim = if is_rising { target + overshoot } else { target - overshoot };
+
+			self.level = aim + (self.level - aim) * coefficient;
+		} else if is_rising {
+			self.level += distance / num_samples;
+		} else {
+			self.level -= distance / num_samples;
+		}
+
+		if (is_rising && self.level >= target) || (!is_rising && self.level <= target) {
+			self.level = target;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+impl NodeBehavior for AdsrNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		let time = |name: &str, default: f32| ParameterDescriptor { smoothing: Smoothing::None, ..ParameterDescriptor::float(name, Some((0.0, MAX_STAGE_TIME)), default, "s") };
+
+		NodeBehaviorInfo {
+			type_name: String::from("AdsrNode"),
+			num_ins: 6,
+			num_outs: 2,
+			parameters: vec![
+				time("attack", 0.01),
+				time("decay", 0.1),
+				ParameterDescriptor { smoothing: Smoothing::None, ..ParameterDescriptor::float("sustain", Some((0.0, 1.0)), 0.7, "") },
+				time("release", 0.3),
+				ParameterDescriptor::boolean("exponential", false)
+			]
+		}
+	}
+
+	fn prepare(&mut self, context: &ProcessContext){
+		self.sample_rate = context.sample_rate;
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		for n in 0..num_frames {
+			let gate = inputs[GATE_INPUT].buffer[n];
+			let retrigger = inputs[RETRIGGER_INPUT].buffer[n];
+			let is_open = gate > 0.0;
+
+			if (self.last_gate <= 0.0 && is_open) || (is_open && self.last_retrigger <= 0.0 && retrigger > 0.0) {
+				self.stage = AdsrStage::Attack;
+			} else if self.last_gate > 0.0 && !is_open && self.stage != AdsrStage::Idle {
+				self.stage = AdsrStage::Release;
+				self.release_from = self.level;
+			}
+
+			self.last_gate = gate;
+			self.last_retrigger = retrigger;
+
+			let attack = (self.attack + inputs[ATTACK_INPUT].buffer[n]).max(0.0);
+			let decay = (self.decay + inputs[DECAY_INPUT].buffer[n]).max(0.0);
+			let sustain = (self.sustain + inputs[SUSTAIN_INPUT].buffer[n]).clamp(0.0, 1.0);
+			let release = (self.release + inputs[RELEASE_INPUT].buffer[n]).max(0.0);
+
+			let mut is_end = false;
+
+			match self.stage {
+				AdsrStage::Idle => {},
+				AdsrStage::Attack => {
+					if self.step_towards(1.0, attack, 1.0, ATTACK_OVERSHOOT) {
+						self.stage = AdsrStage::Decay;
+					}
+				},
+				AdsrStage::Decay => {
+					if self.level <= sustain || self.step_towards(sustain, decay, 1.0 - sustain, DECAY_OVERSHOOT) {
+						self.stage = AdsrStage::Sustain;
+					}
+				},
+				AdsrStage::Sustain => {
+					self.level = sustain;
+				},
+				AdsrStage::Release => {
+					if self.step_towards(0.0, release, self.release_from, DECAY_OVERSHOOT) {
+						self.stage = AdsrStage::Idle;
+						is_end = true;
+					}
+				}
+			}
+
+			outputs[ENVELOPE_OUTPUT].buffer[n] = self.level;
+			outputs[END_OUTPUT].buffer[n] = if is_end { 1.0 } else { 0.0 };
+		}
+	}
+
+	fn get_parameter(&self, id: ParameterId) -> Option<ParameterValue> {
+		match id {
+			ADSR_ATTACK => Some(ParameterValue::Float(self.attack)),
+			ADSR_DECAY => Some(ParameterValue::Float(self.decay)),
+			ADSR_SUSTAIN => Some(ParameterValue::Float(self.sustain)),
+			ADSR_RELEASE => Some(ParameterValue::Float(self.release)),
+			ADSR_EXPONENTIAL => Some(ParameterValue::Boolean(self.is_exponential)),
+			_ => None
+		}
+	}
+
+	fn set_parameter(&mut self, id: ParameterId, value: ParameterValue){
+		match id {
+			ADSR_ATTACK => self.attack = value.as_f32(),
+			ADSR_DECAY => self.decay = value.as_f32(),
+			ADSR_SUSTAIN => self.sustain = value.as_f32(),
+			ADSR_RELEASE => self.release = value.as_f32(),
+			ADSR_EXPONENTIAL => self.is_exponential = value.as_i64() != 0,
+			_ => {}
+		}
+	}
+}
+
+// The end of a segment of a breakpoint envelope, which is reached in a straight line from the end of the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+	// seconds
+	pub duration: f32,
+	pub level: f32
+}
+
+// Goes through its breakpoints once the gate opens, starting from wherever the level is. While the gate stays open,
+// the segments of the loop repeat, and closing it carries on with the segment after the loop. Without a loop
+// the gate only starts the envelope, which then runs to the end.
+pub struct EnvelopeNode {
+	points: Vec<Breakpoint>,
+	// the first and last segment to repeat
+	loop_segments: Option<(usize, usize)>,
+	sample_rate: f32,
+
+	// none when the envelope isn't running
+	segment: Option<usize>,
+	// the level the segment started from, and how many samples of it have gone by.
+	// Counting samples rather than adding up fractions keeps long segments on time.
+	from: f32,
+	elapsed: u64,
+	level: f32,
+
+	last_gate: f32,
+	last_retrigger: f32
+}
+
+impl EnvelopeNode {
+	// The points can't be empty, and the loop has to be in range. The level starts at 0.
+	pub fn new(points: Vec<Breakpoint>, loop_segments: Option<(usize, usize)>) -> EnvelopeNode {
+		EnvelopeNode {
+			points,
+			loop_segments,
+			sample_rate: DEFAULT_SAMPLE_RATE,
+
+			segment: None,
+			from: 0.0,
+			elapsed: 0,
+			level: 0.0,
+
+			last_gate: 0.0,
+			last_retrigger: 0.0
+		}
+	}
+
+	fn start_segment(&mut self, segment: usize){
+		self.segment = if segment < self.points.len() { Some(segment) } else { None };
+		self.from = self.level;
+		self.elapsed = 0;
+	}
+}
+
+impl NodeBehavior for EnvelopeNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		NodeBehaviorInfo {
+			type_name: String::from("EnvelopeNode"),
+			num_ins: 2,
+			num_outs: 2,
+			parameters: Vec::new()
+		}
+	}
+
+	fn prepare(&mut self, context: &ProcessContext){
+		self.sample_rate = context.sample_rate;
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		for n in 0..num_frames {
+			let gate = inputs[GATE_INPUT].buffer[n];
+			let retrigger = inputs[RETRIGGER_INPUT].buffer[n];
+			let is_open = gate > 0.0;
+
+			if (self.last_gate <= 0.0 && is_open) || (is_open && self.last_retrigger <= 0.0 && retrigger > 0.0) {
+				self.start_segment(0);
+			} else if self.last_gate > 0.0 && !is_open {
+				if let (Some(segment), Some((first, last))) = (self.segment, self.loop_segments) {
+					if (first..=last).contains(&segment) {
+						self.start_segment(last + 1);
+					}
+				}
+			}
+
+			self.last_gate = gate;
+			self.last_retrigger = retrigger;
+
+			let mut is_end = false;
+
+			if let Some(segment) = self.segment {
+				let point = self.points[segment];
+				// segments shorter than a sample still take one, so a loop always moves on
+				let num_samples = (f64::from(point.duration) * f64::from(self.sample_rate)).round().max(1.0) as u64;
+
+				self.elapsed += 1;
+
+				if self.elapsed >= num_samples {
+					self.level = point.level;
+
+					match self.loop_segments {
+						Some((first, last)) if is_open && segment == last => {
+							self.start_segment(first);
+							is_end = true;
+						},
+						_ => {
+							self.start_segment(segment + 1);
+							is_end = self.segment.is_none();
+						}
+					}
+				} else {
+					let progress = self.elapsed as f64 / num_samples as f64;
+					self.level = self.from + (point.level - self.from) * progress as f32;
+				}
+			}
+
+			outputs[ENVELOPE_OUTPUT].buffer[n] = self.level;
+			outputs[END_OUTPUT].buffer[n] = if is_end { 1.0 } else { 0.0 };
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn long_segments_end_on_time() {
+		const SAMPLE_RATE: f32 = 48000.0;
+		const BLOCK_SIZE: usize = 256;
+		const NUM_SAMPLES: usize = 60 * 48000;
+
+		let mut node = EnvelopeNode::new(vec![Breakpoint { duration: 60.0, level: 1.0 }], None);
+		node.prepare(&ProcessContext::new(SAMPLE_RATE, BLOCK_SIZE));
+
+		let mut ins: Vec<NodeIn> = (0..2).map(|_| NodeIn::new(Vec::new(), BLOCK_SIZE)).collect();
+		ins[GATE_INPUT].buffer.fill(1.0);
+		let mut outs: Vec<NodeOut> = (0..2).map(|_| NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }).collect();
+
+		let mut levels = Vec::with_capacity(NUM_SAMPLES + BLOCK_SIZE);
+		let mut end = None;
+
+		while end.is_none() && levels.len() <= NUM_SAMPLES {
+			node.update(&ins, &mut outs, BLOCK_SIZE);
+
+			end = outs[END_OUTPUT].buffer.iter().position(|is_end| *is_end > 0.0).map(|n| levels.len() + n);
+			levels.extend_from_slice(&outs[ENVELOPE_OUTPUT].buffer);
+		}
+
+		// the gate opens on the first sample, which is the first step of the segment
+		assert_eq!(end, Some(NUM_SAMPLES - 1));
+		assert_eq!(levels[NUM_SAMPLES - 1], 1.0);
+		assert!((levels[NUM_SAMPLES / 2 - 1] - 0.5).abs() < 1e-6, "halfway at {}", levels[NUM_SAMPLES / 2 - 1]);
+	}
+
+	// Opens the gate for gate_samples, and returns the levels, and where the envelope ended
+	fn render_adsr(node: &mut AdsrNode, gate_samples: usize, num_samples: usize) -> (Vec<f32>, Option<usize>) {
+		const BLOCK_SIZE: usize = 256;
+
+		node.prepare(&ProcessContext::new(48000.0, BLOCK_SIZE));
+
+		let mut ins: Vec<NodeIn> = (0..6).map(|_| NodeIn::new(Vec::new(), BLOCK_SIZE)).collect();
+		let mut outs: Vec<NodeOut> = (0..2).map(|_| NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }).collect();
+
+		let mut levels = Vec::with_capacity(num_samples);
+		let mut end = None;
+
+		while levels.len() < num_samples {
+			for (n, gate) in ins[GATE_INPUT].buffer.iter_mut().enumerate() {
+				*gate = if levels.len() + n < gate_samples { 1.0 } else { 0.0 };
+			}
+
+			node.update(&ins, &mut outs, BLOCK_SIZE);
+
+			if let Some(n) = outs[END_OUTPUT].buffer.iter().position(|is_end| *is_end > 0.0) {
+				end = end.or(Some(levels.len() + n));
+			}
+			levels.extend_from_slice(&outs[ENVELOPE_OUTPUT].buffer);
+		}
+
+		(levels, end)
+	}
+
+	#[test]
+	fn adsr_stages_take_their_time() {
+		// attack 0.1 s, decay 0.2 s to 0.6, release 0.3 s, in samples at 48 kHz
+		const ATTACK: usize = 4800;
+		const DECAY: usize = 9600;
+		const RELEASE: usize = 14400;
+		const SUSTAIN: f32 = 0.6;
+
+		for is_exponential in [false, true] {
+			// released in the middle of the sustain, and again in the middle of the decay, from a lower level
+			for gate_samples in [ATTACK + DECAY + 1000, ATTACK + DECAY / 2] {
+				let mut node = AdsrNode::new();
+				node.set_parameter(ADSR_ATTACK, ParameterValue::Float(0.1));
+				node.set_parameter(ADSR_DECAY, ParameterValue::Float(0.2));
+				node.set_parameter(ADSR_SUSTAIN, ParameterValue::Float(SUSTAIN));
+				node.set_parameter(ADSR_RELEASE, ParameterValue::Float(0.3));
+				node.set_parameter(ADSR_EXPONENTIAL, ParameterValue::Boolean(is_exponential));
+
+				let (levels, end) = render_adsr(&mut node, gate_samples, gate_samples + RELEASE + 1000);
+
+				// the steps of the level are added up in single precision, which is good to about a thousandth of a stage.
+				// The first sample of the gate is the first step of the attack.
+				let near = |at: usize, expected: usize| at.abs_diff(expected) <= expected / 1000 + 1;
+
+				let attack_end = levels.iter().position(|level| *level == 1.0).unwrap();
+				assert!(near(attack_end, ATTACK - 1), "exponential {}: the attack ended at {}", is_exponential, attack_end);
+
+				if gate_samples > ATTACK + DECAY {
+					let decay_end = levels.iter().position(|level| *level == SUSTAIN).unwrap();
+					assert!(near(decay_end, ATTACK + DECAY - 1), "exponential {}: the decay ended at {}", is_exponential, decay_end);
+				}
+
+				let end = end.unwrap();
+				assert!(near(end, gate_samples + RELEASE - 1), "exponential {}: released at {}, ended at {}", is_exponential, gate_samples, end);
+				assert_eq!(levels[end], 0.0);
+			}
+		}
+	}
+}
\ No newline at end of file
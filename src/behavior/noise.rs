@@ -0,0 +1,306 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, ProcessContext, DEFAULT_SAMPLE_RATE};
+
+// Spreads the seeds of nodes created in the same instant
+static SEED_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A seed for nodes that weren't given one, different every time
+pub fn random_seed() -> u64 {
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64);
+
+	nanos ^ SEED_COUNTER.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+}
+
+// xorshift64*, which is fast, small and good enough for audio. The same seed always gives the same numbers,
+// so offline renders of patches with seeded noise come out the same every time.
+pub struct Rng {
+	state: u64
+}
+
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		// one round of splitmix64, so similar seeds don't start out similar, and the state is never 0
+		let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^= z >> 31;
+
+		Rng {
+			state: if z == 0 { 1 } else { z }
+		}
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+
+		self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	// Uniform in [-1, 1)
+	pub fn next_f32(&mut self) -> f32 {
+		// the top 24 bits, as many as an f32 holds exactly
+		(self.next_u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseColor {
+	// the same power at every frequency
+	White,
+	// 3 dB less power every octave up
+	Pink,
+	// 6 dB less power every octave up
+	Brown
+}
+
+impl NoiseColor {
+	pub fn from_name(name: &str) -> Option<NoiseColor> {
+		match name {
+			"white" => Some(NoiseColor::White),
+			"pink" => Some(NoiseColor::Pink),
+			"brown" | "red" => Some(NoiseColor::Brown),
+			_ => None
+		}
+	}
+}
+
+// The pole of the leaky integrator that makes brown noise at 44.1 kHz, where it starts falling off at about 140 Hz
+const BROWN_POLE: f32 = 1.0 / 1.02;
+const BROWN_SAMPLE_RATE: f32 = 44100.0;
+
+// Noise between about -1 and 1. Pink noise is white noise through Paul Kellet's filter, which is accurate
+// to a fraction of a dB from 9 Hz up at 44.1 kHz. At other rates the filter covers other frequencies, but as pink noise
+// has the same power in every octave it still sounds the same, down to a proportionally higher limit.
+// Brown noise is white noise through a leaky integrator, so it doesn't wander off. Its pole moves with the rate
+// so it falls off from the same frequency, and its level follows the white noise spreading over more or fewer Hz.
+pub struct NoiseNode {
+	color: NoiseColor,
+	rng: Rng,
+	// the states of the pink filter's poles, or the integrator's for brown noise
+	filter: [f32; 7],
+
+	brown_pole: f32,
+	brown_level: f32
+}
+
+impl NoiseNode {
+	pub fn new(color: NoiseColor, seed: u64) -> NoiseNode {
+		let mut node = NoiseNode {
+			color,
+			rng: Rng::new(seed),
+			filter: [0.0; 7],
+
+			brown_pole: BROWN_POLE,
+			brown_level: 1.0
+		};
+
+		node.set_sample_rate(DEFAULT_SAMPLE_RATE);
+		node
+	}
+
+	fn set_sample_rate(&mut self, sample_rate: f32){
+		self.brown_pole = BROWN_POLE.powf(BROWN_SAMPLE_RATE / sample_rate);
+		self.brown_level = (sample_rate / BROWN_SAMPLE_RATE).sqrt();
+	}
+
+	fn next_pink(&mut self, white: f32) -> f32 {
+		let b = &mut self.filter;
+
+		b[0] = 0.99886 * b[0] + white * 0.0555179;
+		b[1] = 0.99332 * b[1] + white * 0.0750759;
+		b[2] = 0.96900 * b[2] + white * 0.153852;
+		b[3] = 0.86650 * b[3] + white * 0.3104856;
+		b[4] = 0.55000 * b[4] + white * 0.5329522;
+		b[5] = -0.7616 * b[5] - white * 0.0168980;
+
+		let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+		b[6] = white * 0.115926;
+
+		pink * 0.11
+	}
+
+	fn next_brown(&mut self, white: f32) -> f32 {
+		self.filter[0] = self.brown_pole * self.filter[0] + (1.0 - self.brown_pole) * white;
+
+		self.filter[0] * 3.5 * self.brown_level
+	}
+}
+
+impl NodeBehavior for NoiseNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		NodeBehaviorInfo {
+			type_name: String::from("NoiseNode"),
+			num_ins: 0,
+			num_outs: 1,
+			parameters: Vec::new()
+		}
+	}
+
+	fn prepare(&mut self, context: &ProcessContext){
+		self.set_sample_rate(context.sample_rate);
+	}
+
+	fn update(&mut self, _inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let output = outputs.get_mut(0).unwrap();
+
+		for n in 0..num_frames {
+			let white = self.rng.next_f32();
+
+			output.buffer[n] = match self.color {
+				NoiseColor::White => white,
+				NoiseColor::Pink => self.next_pink(white),
+				NoiseColor::Brown => self.next_brown(white)
+			};
+		}
+	}
+}
+
+const SIGNAL_INPUT: usize = 0;
+// the signal is latched on the sample where this goes above zero
+const TRIGGER_INPUT: usize = 1;
+
+// Holds on to the value of its signal input from one trigger to the next.
+// With noise going in, it makes the stepped random voltages of modular synths.
+pub struct SampleAndHoldNode {
+	held: f32,
+	last_trigger: f32
+}
+
+impl SampleAndHoldNode {
+	pub fn new() -> SampleAndHoldNode {
+		SampleAndHoldNode {
+			held: 0.0,
+			last_trigger: 0.0
+		}
+	}
+}
+
+impl NodeBehavior for SampleAndHoldNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		NodeBehaviorInfo {
+			type_name: String::from("SampleAndHoldNode"),
+			num_ins: 2,
+			num_outs: 1,
+			parameters: Vec::new()
+		}
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let output = outputs.get_mut(0).unwrap();
+
+		for n in 0..num_frames {
+			let trigger = inputs[TRIGGER_INPUT].buffer[n];
+
+			if self.last_trigger <= 0.0 && trigger > 0.0 {
+				self.held = inputs[SIGNAL_INPUT].buffer[n];
+			}
+
+			self.last_trigger = trigger;
+			output.buffer[n] = self.held;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::behavior::register_node_recipes_once;
+	use crate::behavior::wavetable::fft;
+	use crate::core::node::NodeParameters;
+	use crate::core::node_graph::NodeGraph;
+
+	const BLOCK_SIZE: usize = 256;
+
+	fn render(node: &mut NoiseNode, sample_rate: f32, len: usize) -> Vec<f32> {
+		node.prepare(&ProcessContext::new(sample_rate, BLOCK_SIZE));
+
+		let mut outs = vec![NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }];
+		let mut samples = Vec::with_capacity(len);
+
+		while samples.len() < len {
+			node.update(&[], &mut outs, BLOCK_SIZE);
+			samples.extend_from_slice(&outs[0].buffer);
+		}
+
+		samples
+	}
+
+	// The power of every octave from 125 Hz to 16 kHz in dB, averaged over windows of the signal
+	fn octave_powers(signal: &[f32], sample_rate: f32) -> Vec<f64> {
+		const LEN: usize = 8192;
+
+		let window: Vec<f64> = (0..LEN).map(|i| 0.5 - 0.5 * (std::f64::consts::TAU * i as f64 / LEN as f64).cos()).collect();
+		let window_power: f64 = window.iter().map(|w| w * w).sum();
+		let bin_freq = f64::from(sample_rate) / LEN as f64;
+
+		let mut powers = [0.0; 7];
+		let num_windows = signal.len() / LEN;
+
+		for chunk in signal.chunks_exact(LEN) {
+			let mut re: Vec<f64> = chunk.iter().zip(window.iter()).map(|(sample, w)| f64::from(*sample) * w).collect();
+			let mut im = vec![0.0; LEN];
+			fft(&mut re, &mut im, false);
+
+			for (octave, power) in powers.iter_mut().enumerate() {
+				let low = 125.0 * f64::from(1 << octave);
+				let bins = (low / bin_freq).ceil() as usize..(2.0 * low / bin_freq).ceil() as usize;
+
+				*power += bins.map(|bin| 2.0 * (re[bin] * re[bin] + im[bin] * im[bin]) / (LEN as f64 * window_power)).sum::<f64>();
+			}
+		}
+
+		powers.iter().map(|power| 10.0 * (power / num_windows as f64).log10()).collect()
+	}
+
+	#[test]
+	fn seeds_make_the_same_noise() {
+		for color in [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown] {
+			let first = render(&mut NoiseNode::new(color, 1234), 44100.0, 4096);
+			let again = render(&mut NoiseNode::new(color, 1234), 44100.0, 4096);
+			let other = render(&mut NoiseNode::new(color, 1235), 44100.0, 4096);
+
+			assert_eq!(first, again, "{:?}", color);
+			assert!(first.iter().zip(other.iter()).all(|(a, b)| a != b), "{:?}", color);
+		}
+	}
+
+	#[test]
+	fn random_seeds_are_saved() {
+		register_node_recipes_once();
+
+		let (mut graph, _renderer) = NodeGraph::new(ProcessContext::new(44100.0, BLOCK_SIZE));
+		graph.add_node_by_recipe("noise", "NoiseNode", NodeParameters::new()).unwrap();
+
+		let patch = graph.to_patch().unwrap();
+		let seed = patch.nodes[0].parameters.get("seed").and_then(|seed| seed.as_u64()).unwrap();
+
+		let (loaded, _renderer) = NodeGraph::from_patch(&patch, ProcessContext::new(44100.0, BLOCK_SIZE)).unwrap();
+		let saved_again = loaded.to_patch().unwrap();
+
+		assert_eq!(saved_again.nodes[0].parameters.get("seed").and_then(|seed| seed.as_u64()), Some(seed));
+	}
+
+	#[test]
+	fn colors_sound_the_same_at_every_rate() {
+		for color in [NoiseColor::Pink, NoiseColor::Brown] {
+			let reference = octave_powers(&render(&mut NoiseNode::new(color, 1), 44100.0, 1 << 20), 44100.0);
+
+			// pink noise has the same power in every octave, brown noise 3 dB less every octave up
+			let drop = if color == NoiseColor::Pink { 0.0 } else { -3.0 };
+			for pair in reference.windows(2).skip(2) {
+				assert!((pair[1] - pair[0] - drop).abs() < 1.0, "{:?} octaves at 44.1 kHz: {:?}", color, reference);
+			}
+
+			for sample_rate in [48000.0, 96000.0] {
+				let powers = octave_powers(&render(&mut NoiseNode::new(color, 1), sample_rate, 1 << 21), sample_rate);
+
+				for (power, expected) in powers.iter().zip(reference.iter()) {
+					assert!((power - expected).abs() < 1.0, "{:?} octaves at {} Hz: {:?}, at 44.1 kHz: {:?}", color, sample_rate, powers, reference);
+				}
+			}
+		}
+	}
+}
\ No newline at end of file
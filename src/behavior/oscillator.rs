@@ -0,0 +1,352 @@
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, ProcessContext, DEFAULT_SAMPLE_RATE};
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::smoothing::{SmoothedValue, Smoothing};
+
+const FREQUENCY_INPUT: usize = 0;
+// restarts the cycle where the signal crosses zero going up, in between samples, to follow another oscillator
+const SYNC_INPUT: usize = 1;
+// restarts the cycle on the sample where the signal goes above zero, like a note starting
+const RESET_INPUT: usize = 2;
+// added to the width parameter, for pulses only
+const WIDTH_INPUT: usize = 3;
+
+const OUTPUT: usize = 0;
+// where the cycle is, in [0, 1), lined up with the output so other nodes can follow it
+const PHASE_OUTPUT: usize = 1;
+
+// Beyond this many cycles in one sample, the phase input jumped rather than moved, and there's nothing to smooth
+const MAX_SMOOTHED_DELTA: f64 = 1.0;
+
+const PULSE_WIDTH: ParameterId = 0;
+
+// The pulse never gets thinner than this, so its edges stay apart
+const MIN_PULSE_WIDTH: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OscillatorShape {
+	Saw,
+	Pulse,
+	Triangle
+}
+
+// A point of the cycle where the waveform jumps or bends, when the phase goes through it forwards
+struct Corner {
+	phase: f64,
+	// change of value
+	jump: f64,
+	// change of slope, per unit of phase
+	bend: f64
+}
+
+impl OscillatorShape {
+	// The naive waveform between -1 and 1, for a phase in [0, 1)
+	fn value(self, phase: f64, width: f64) -> f64 {
+		match self {
+			OscillatorShape::Saw => 2.0 * phase - 1.0,
+			OscillatorShape::Pulse => if phase < width { 1.0 } else { -1.0 },
+			OscillatorShape::Triangle => if phase < 0.5 { 4.0 * phase - 1.0 } else { 3.0 - 4.0 * phase }
+		}
+	}
+
+	fn slope(self, phase: f64) -> f64 {
+		match self {
+			OscillatorShape::Saw => 2.0,
+			OscillatorShape::Pulse => 0.0,
+			OscillatorShape::Triangle => if phase < 0.5 { 4.0 } else { -4.0 }
+		}
+	}
+
+	fn corners(self, width: f64) -> [Corner; 2] {
+		match self {
+			OscillatorShape::Saw => [Corner { phase: 0.0, jump: -2.0, bend: 0.0 }, Corner { phase: 0.5, jump: 0.0, bend: 0.0 }],
+			OscillatorShape::Pulse => [Corner { phase: 0.0, jump: 2.0, bend: 0.0 }, Corner { phase: width, jump: -2.0, bend: 0.0 }],
+			OscillatorShape::Triangle => [Corner { phase: 0.0, jump: 0.0, bend: 8.0 }, Corner { phase: 0.5, jump: 0.0, bend: -8.0 }]
+		}
+	}
+
+	fn type_name(self) -> &'static str {
+		match self {
+			OscillatorShape::Saw => "SawNode",
+			OscillatorShape::Pulse => "PulseNode",
+			OscillatorShape::Triangle => "TriangleNode"
+		}
+	}
+}
+
+// An oscillator with the jumps and bends of its waveform smoothed out by PolyBLEP and PolyBLAMP residuals,
+// which keeps most of the aliasing out of the audible range. The residual of a discontinuity reaches back
+// one sample, so the output lags the inputs by one sample.
+pub struct OscillatorNode {
+	shape: OscillatorShape,
+	sample_rate: f32,
+	// where the cycle is, in [0, 1), with the phase input added. Kept in double precision and
+	// wrapped every sample, so the frequency stays exact however long it runs.
+	phase: f64,
+	width: SmoothedValue,
+
+	// the sample before the one being computed, still open to corrections for discontinuities that come after it
+	held: f64,
+	held_phase: f64,
+
+	last_sync: f32,
+	last_reset: f32,
+	last_phase_offset: f64
+}
+
+impl OscillatorNode {
+	pub fn new(shape: OscillatorShape) -> OscillatorNode {
+		OscillatorNode {
+			shape,
+			sample_rate: DEFAULT_SAMPLE_RATE,
+			phase: 0.0,
+			width: SmoothedValue::new(0.5, Smoothing::default()),
+
+			held: 0.0,
+			held_phase: 0.0,
+
+			last_sync: 0.0,
+			last_reset: 0.0,
+			last_phase_offset: 0.0
+		}
+	}
+
+	// The last input, added to the phase in cycles. It can go either way, for phase modulation through zero.
+	fn phase_input(&self) -> usize {
+		if self.shape == OscillatorShape::Pulse { WIDTH_INPUT + 1 } else { WIDTH_INPUT }
+	}
+
+	// Adds the residuals of a discontinuity that happens distance samples before the current sample.
+	// jump is the change of value, bend the change of slope per sample.
+	fn add_discontinuity(&mut self, jump: f64, bend: f64, distance: f64, current: &mut f64){
+		let before = distance;
+		let after = 1.0 - distance;
+
+		self.held += jump * before * before / 2.0 + bend * before * before * before / 6.0;
+		*current += -jump * after * after / 2.0 + bend * after * after * after / 6.0;
+	}
+
+	// Moves the phase by delta over the part of the sample period that starts at start and lasts duration,
+	// both in samples since the previous sample, going through every corner on the way
+	fn advance(&mut self, delta: f64, start: f64, duration: f64, width: f64, current: &mut f64){
+		if delta == 0.0 {
+			return;
+		}
+
+		if delta.abs() >= MAX_SMOOTHED_DELTA {
+			self.phase = (self.phase + delta).rem_euclid(1.0);
+			return;
+		}
+
+		let from = self.phase;
+		let to = self.phase + delta;
+		// going through a corner backwards undoes its jump, while the slope still changes the same way in time
+		let direction = delta.signum();
+		let speed = delta.abs() / duration;
+
+		for corner in self.shape.corners(width).iter().filter(|corner| corner.jump != 0.0 || corner.bend != 0.0) {
+			// every corner_phase + k strictly past from, up to and including to
+			let mut cycle = if delta > 0.0 { (from - corner.phase).floor() + 1.0 } else { (from - corner.phase).ceil() - 1.0 };
+
+			loop {
+				let level = cycle + corner.phase;
+
+				if (delta > 0.0 && level > to) || (delta < 0.0 && level < to) {
+					break;
+				}
+
+				let time = start + (level - from) / delta * duration;
+				self.add_discontinuity(corner.jump * direction, corner.bend * speed, 1.0 - time, current);
+
+				cycle += direction;
+			}
+		}
+
+		self.phase = to.rem_euclid(1.0);
+	}
+
+	// Starts the cycle over, distance samples before the current sample. The phase input still applies,
+	// so the phase goes to phase_offset rather than 0.
+	fn restart(&mut self, step: f64, width: f64, phase_offset: f64, distance: f64, current: &mut f64){
+		let phase = phase_offset.rem_euclid(1.0);
+		let jump = self.shape.value(phase, width) - self.shape.value(self.phase, width);
+		let bend = (self.shape.slope(phase) - self.shape.slope(self.phase)) * step;
+
+		self.add_discontinuity(jump, bend, distance, current);
+		self.phase = phase;
+	}
+}
+
+impl NodeBehavior for OscillatorNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		let is_pulse = self.shape == OscillatorShape::Pulse;
+
+		NodeBehaviorInfo {
+			type_name: String::from(self.shape.type_name()),
+			num_ins: self.phase_input() + 1,
+			num_outs: 2,
+			parameters: if is_pulse {
+				vec![ParameterDescriptor::float("width", Some((MIN_PULSE_WIDTH, 1.0 - MIN_PULSE_WIDTH)), 0.5, "")]
+			} else {
+				Vec::new()
+			}
+		}
+	}
+
+	fn prepare(&mut self, context: &ProcessContext){
+		self.sample_rate = context.sample_rate;
+		self.width.set_sample_rate(context.sample_rate);
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let phase_input = self.phase_input();
+		let nyquist = self.sample_rate / 2.0;
+
+		for n in 0..num_frames {
+			// above nyquist, the residuals couldn't keep up anyway
+			let step = f64::from(inputs[FREQUENCY_INPUT].buffer[n].clamp(-nyquist, nyquist)) / f64::from(self.sample_rate);
+			// the other shapes have their phase input where the width input of pulses is
+			let width = if self.shape == OscillatorShape::Pulse {
+				f64::from(self.width.next() + inputs[WIDTH_INPUT].buffer[n]).clamp(MIN_PULSE_WIDTH, 1.0 - MIN_PULSE_WIDTH)
+			} else {
+				0.5
+			};
+
+			// the phase input moves the phase along with the frequency, and is spread evenly over the sample
+			let phase_offset = f64::from(inputs[phase_input].buffer[n]);
+			let delta = step + phase_offset - self.last_phase_offset;
+
+			let mut current = 0.0;
+
+			let sync = inputs[SYNC_INPUT].buffer[n];
+			if self.last_sync <= 0.0 && sync > 0.0 {
+				// where the line between the two samples crosses zero
+				let time = f64::from(self.last_sync / (self.last_sync - sync));
+
+				self.advance(delta * time, 0.0, time, width, &mut current);
+				self.restart(step, width, self.last_phase_offset + (phase_offset - self.last_phase_offset) * time, 1.0 - time, &mut current);
+				self.advance(delta * (1.0 - time), time, 1.0 - time, width, &mut current);
+			} else {
+				self.advance(delta, 0.0, 1.0, width, &mut current);
+			}
+			self.last_sync = sync;
+			self.last_phase_offset = phase_offset;
+
+			let reset = inputs[RESET_INPUT].buffer[n];
+			if self.last_reset <= 0.0 && reset > 0.0 {
+				self.restart(step, width, phase_offset, 0.0, &mut current);
+			}
+			self.last_reset = reset;
+
+			current += self.shape.value(self.phase, width);
+
+			outputs[OUTPUT].buffer[n] = self.held as f32;
+			outputs[PHASE_OUTPUT].buffer[n] = self.held_phase as f32;
+			self.held = current;
+			self.held_phase = self.phase;
+		}
+	}
+
+	fn get_parameter(&self, id: ParameterId) -> Option<ParameterValue> {
+		match id {
+			PULSE_WIDTH if self.shape == OscillatorShape::Pulse => Some(ParameterValue::Float(self.width.target())),
+			_ => None
+		}
+	}
+
+	fn set_parameter(&mut self, id: ParameterId, value: ParameterValue){
+		if id == PULSE_WIDTH && self.shape == OscillatorShape::Pulse {
+			self.width.set_target(value.as_f32());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::behavior::tests::{alias_ratio, max_step};
+	use crate::core::node::{NodeIn, NodeOut};
+
+	const SAMPLE_RATE: f32 = 44100.0;
+	const BLOCK_SIZE: usize = 256;
+
+	// Runs the node over whole signals, one per input, and returns its signal output
+	fn render(node: &mut OscillatorNode, inputs: &[Vec<f32>]) -> Vec<f32> {
+		node.prepare(&ProcessContext::new(SAMPLE_RATE, BLOCK_SIZE));
+
+		let num_ins = node.get_info().num_ins;
+		let len = inputs[0].len();
+
+		let mut ins: Vec<NodeIn> = (0..num_ins).map(|_| NodeIn::new(Vec::new(), BLOCK_SIZE)).collect();
+		let mut outs: Vec<NodeOut> = (0..2).map(|_| NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }).collect();
+		let mut samples = Vec::with_capacity(len);
+
+		for start in (0..len).step_by(BLOCK_SIZE) {
+			let num_frames = BLOCK_SIZE.min(len - start);
+
+			for (inp, signal) in ins.iter_mut().zip(inputs.iter()) {
+				inp.buffer[..num_frames].copy_from_slice(&signal[start..start + num_frames]);
+			}
+
+			node.update(&ins, &mut outs, num_frames);
+			samples.extend_from_slice(&outs[OUTPUT].buffer[..num_frames]);
+		}
+
+		samples
+	}
+
+	fn constant(value: f32, len: usize) -> Vec<f32> {
+		vec![value; len]
+	}
+
+	// Inputs for an oscillator at freq, with nothing else connected
+	fn inputs(shape: OscillatorShape, freq: f32, len: usize) -> Vec<Vec<f32>> {
+		let num_ins = OscillatorNode::new(shape).get_info().num_ins;
+		let mut inputs = vec![constant(freq, len)];
+		inputs.extend((1..num_ins).map(|_| constant(0.0, len)));
+		inputs
+	}
+
+	#[test]
+	fn high_notes_alias_little() {
+		// the naive waveforms come out at about -10 dB for saws and pulses, and -30 dB for triangles
+		for (shape, limit) in [(OscillatorShape::Saw, -20.0), (OscillatorShape::Pulse, -20.0), (OscillatorShape::Triangle, -33.0)] {
+			for freq in [2489.0, 5012.3] {
+				let samples = render(&mut OscillatorNode::new(shape), &inputs(shape, freq, 10000));
+				let ratio = alias_ratio(&samples, freq);
+
+				assert!(ratio < limit, "{:?} at {} Hz aliases at {:.1} dB", shape, freq, ratio);
+			}
+		}
+	}
+
+	#[test]
+	fn sync_and_reset_stay_smooth() {
+		const LEN: usize = 44100;
+		const FREQ: f32 = 220.0;
+
+		// The largest jump of any shape is 2, which the residuals spread over two samples so that no more
+		// than three quarters of it falls between two of them, on top of what the waveform moves by itself.
+		// A restart that isn't smoothed jumps the full way.
+		let limit = 0.75 * 2.0 + 4.0 * FREQ / SAMPLE_RATE;
+
+		// a master sweeping from 90 to 170 Hz, so the cycle gets cut short at every point along it
+		let mut master_phase = 0.0;
+		let master: Vec<f32> = (0..LEN).map(|n| {
+			master_phase += (90.0 + 80.0 * n as f64 / LEN as f64) / f64::from(SAMPLE_RATE);
+			(std::f64::consts::TAU * master_phase).sin() as f32
+		}).collect();
+		let notes: Vec<f32> = (0..LEN).map(|n| if n % 337 < 100 { 1.0 } else { 0.0 }).collect();
+
+		for shape in [OscillatorShape::Saw, OscillatorShape::Pulse, OscillatorShape::Triangle] {
+			let mut synced = inputs(shape, FREQ, LEN);
+			synced[SYNC_INPUT] = master.clone();
+			let step = max_step(&render(&mut OscillatorNode::new(shape), &synced));
+			assert!(step < limit, "{:?} synced jumps by {}", shape, step);
+
+			let mut reset = inputs(shape, FREQ, LEN);
+			reset[RESET_INPUT] = notes.clone();
+			let step = max_step(&render(&mut OscillatorNode::new(shape), &reset));
+			assert!(step < limit, "{:?} reset jumps by {}", shape, step);
+		}
+	}
+}
\ No newline at end of file
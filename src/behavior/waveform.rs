@@ -1,4 +1,4 @@
-use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut};
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, ProcessContext, DEFAULT_SAMPLE_RATE};
 
 pub struct WaveformNode {
     waveform: Vec<f32>
@@ -7,7 +7,7 @@ pub struct WaveformNode {
 impl WaveformNode {
     pub fn new(waveform: Vec<f32>) -> WaveformNode {
         WaveformNode {
-            waveform: waveform
+            waveform
         }
     }
 }
@@ -17,15 +17,16 @@ impl NodeBehavior for WaveformNode {
         NodeBehaviorInfo {
             type_name: String::from("WaveformNode"),
             num_ins: 0,
-            num_outs: 1
+            num_outs: 1,
+            parameters: Vec::new()
         }
     }
 
-    fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>){
+    fn update(&mut self, _inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
         let output = outputs.get_mut(0).unwrap();
         let k = self.waveform.len();
 
-        for n in 0..output.buffer.len() {
+        for n in 0..num_frames {
             output.buffer[n] = self.waveform[n%k];
         }
     }
@@ -35,14 +36,26 @@ impl NodeBehavior for WaveformNode {
     }
 }
 
+const SIN_FREQUENCY_INPUT: usize = 0;
+// added to the phase, in cycles. It can go either way, for phase modulation through zero.
+const SIN_PHASE_INPUT: usize = 1;
+
+const SIN_OUTPUT: usize = 0;
+// where the cycle is, in [0, 1), so other nodes can follow it
+const SIN_PHASE_OUTPUT: usize = 1;
+
 pub struct SinNode {
-    clock: f32
+    sample_rate: f32,
+    // where the cycle is without the phase input, in [0, 1). Kept in double precision and
+    // wrapped every sample, so the frequency stays exact however long it runs.
+    phase: f64
 }
 
 impl SinNode {
     pub fn new() -> SinNode {
         SinNode {
-            clock: 0.0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            phase: 0.0
         }
     }
 }
@@ -51,18 +64,29 @@ impl NodeBehavior for SinNode {
     fn get_info(&self) -> NodeBehaviorInfo {
         NodeBehaviorInfo {
             type_name: String::from("SinNode"),
-            num_ins: 1,
-            num_outs: 1
+            num_ins: 2,
+            num_outs: 2,
+            parameters: Vec::new()
         }
     }
 
-    fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>){
-        let freq_buffer = &inputs.get(0).unwrap().buffer;
-        let output = outputs.get_mut(0).unwrap();
+    fn prepare(&mut self, context: &ProcessContext){
+        // the phase is in cycles, so it stays where it was
+        self.sample_rate = context.sample_rate;
+    }
+
+    fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+        let freq_buffer = &inputs[SIN_FREQUENCY_INPUT].buffer;
+        let phase_buffer = &inputs[SIN_PHASE_INPUT].buffer;
+        let sample_rate = f64::from(self.sample_rate);
+
+        for n in 0..num_frames {
+            let phase = (self.phase + f64::from(phase_buffer[n])).rem_euclid(1.0);
+
+            outputs[SIN_OUTPUT].buffer[n] = (phase * std::f64::consts::TAU).sin() as f32;
+            outputs[SIN_PHASE_OUTPUT].buffer[n] = phase as f32;
 
-        for n in 0..output.buffer.len() {
-            output.buffer[n] = (self.clock * 2.0 * std::f32::consts::PI / 44100.0).sin();
-            self.clock = (self.clock + freq_buffer[n]) % 44100.0;
+            self.phase = (self.phase + f64::from(freq_buffer[n]) / sample_rate).rem_euclid(1.0);
         }
     }
 
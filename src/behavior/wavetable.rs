@@ -0,0 +1,307 @@
+use std::f64::consts::TAU;
+use crate::core::node::{NodeBehavior, NodeBehaviorInfo, NodeIn, NodeOut, ProcessContext, DEFAULT_SAMPLE_RATE};
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::smoothing::{SmoothedValue, Smoothing};
+
+const FREQUENCY_INPUT: usize = 0;
+// added to the position parameter, to move through the stack of tables
+const POSITION_INPUT: usize = 1;
+// added to the phase, in cycles, like the phase input of the other oscillators
+const PHASE_INPUT: usize = 2;
+
+const OUTPUT: usize = 0;
+const PHASE_OUTPUT: usize = 1;
+
+const WAVETABLE_POSITION: ParameterId = 0;
+
+// Harmonics kept in the first mip level. Every next level keeps half as many, down to the fundamental alone.
+const MAX_HARMONICS: usize = 1024;
+const NUM_MIP_LEVELS: usize = 11;
+
+// Every level has at least 4 samples per period of its highest harmonic, and the levels with few harmonics
+// get more than that, or linear interpolation would add images of them that fold back below nyquist
+const MIN_MIP_SIZE: usize = 256;
+const MAX_MIP_SIZE: usize = 4 * MAX_HARMONICS;
+
+// Plays a stack of single cycle tables at the frequency of its input. The position crossfades between neighbouring
+// tables, and every table is rebuilt at a few bandwidths up front, so the one played never has harmonics above nyquist.
+pub struct WavetableNode {
+	// the mip levels of every table, from the most harmonics to the fewest
+	tables: Vec<Vec<Vec<f32>>>,
+	sample_rate: f32,
+	// where the cycle is, in [0, 1), without the phase input
+	phase: f64,
+	position: SmoothedValue
+}
+
+impl WavetableNode {
+	// Every table is one cycle, they don't need to be of the same length. Building the mip levels takes
+	// a few FFTs per table, so this should stay off the render thread.
+	pub fn new(tables: Vec<Vec<f32>>) -> WavetableNode {
+		WavetableNode {
+			tables: tables.iter().map(|table| build_mip_levels(table)).collect(),
+			sample_rate: DEFAULT_SAMPLE_RATE,
+			phase: 0.0,
+			position: SmoothedValue::new(0.0, Smoothing::default())
+		}
+	}
+
+	// The level with the most harmonics that all stay below nyquist at this frequency
+	fn get_mip_level(&self, freq: f32) -> usize {
+		let max_harmonics = self.sample_rate / 2.0 / freq.abs();
+
+		if max_harmonics >= MAX_HARMONICS as f32 {
+			0
+		} else {
+			((MAX_HARMONICS as f32 / max_harmonics).log2().ceil() as usize).min(NUM_MIP_LEVELS - 1)
+		}
+	}
+}
+
+impl NodeBehavior for WavetableNode {
+	fn get_info(&self) -> NodeBehaviorInfo {
+		NodeBehaviorInfo {
+			type_name: String::from("WavetableNode"),
+			num_ins: 3,
+			num_outs: 2,
+			parameters: vec![
+				ParameterDescriptor::float("position", Some((0.0, 1.0)), 0.0, "")
+			]
+		}
+	}
+
+	fn prepare(&mut self, context: &ProcessContext){
+		self.sample_rate = context.sample_rate;
+		self.position.set_sample_rate(context.sample_rate);
+	}
+
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize){
+		let last_table = self.tables.len() - 1;
+		let sample_rate = f64::from(self.sample_rate);
+
+		for n in 0..num_frames {
+			let freq = inputs[FREQUENCY_INPUT].buffer[n];
+			let phase = (self.phase + f64::from(inputs[PHASE_INPUT].buffer[n])).rem_euclid(1.0);
+			let level = self.get_mip_level(freq);
+
+			let position = (self.position.next() + inputs[POSITION_INPUT].buffer[n]).clamp(0.0, 1.0) * last_table as f32;
+			let table_idx = (position as usize).min(last_table);
+			let fade = position - table_idx as f32;
+
+			let mut out = read_table(&self.tables[table_idx][level], phase);
+			if fade > 0.0 {
+				out += (read_table(&self.tables[table_idx + 1][level], phase) - out) * fade;
+			}
+
+			outputs[OUTPUT].buffer[n] = out;
+			outputs[PHASE_OUTPUT].buffer[n] = phase as f32;
+
+			self.phase = (self.phase + f64::from(freq) / sample_rate).rem_euclid(1.0);
+		}
+	}
+
+	fn get_parameter(&self, id: ParameterId) -> Option<ParameterValue> {
+		match id {
+			WAVETABLE_POSITION => Some(ParameterValue::Float(self.position.target())),
+			_ => None
+		}
+	}
+
+	fn set_parameter(&mut self, id: ParameterId, value: ParameterValue){
+		if id == WAVETABLE_POSITION {
+			self.position.set_target(value.as_f32());
+		}
+	}
+}
+
+// Linear interpolation between the two samples around the phase, wrapping around the end of the cycle
+fn read_table(table: &[f32], phase: f64) -> f32 {
+	let position = phase * table.len() as f64;
+	let idx = (position as usize).min(table.len() - 1);
+	let fraction = (position - idx as f64) as f32;
+
+	let a = table[idx];
+	let b = table[(idx + 1) % table.len()];
+
+	a + (b - a) * fraction
+}
+
+// Splits a table into its harmonics, and adds them back up to the limit of every level
+fn build_mip_levels(table: &[f32]) -> Vec<Vec<f32>> {
+	let num_harmonics = MAX_HARMONICS.min((table.len().max(2) - 1) / 2);
+	let spectrum = get_harmonics(table, num_harmonics);
+
+	(0..NUM_MIP_LEVELS).map(|level| {
+		let level_harmonics = MAX_HARMONICS >> level;
+		let size = (4 * level_harmonics).clamp(MIN_MIP_SIZE, MAX_MIP_SIZE);
+
+		let mut re = vec![0.0; size];
+		let mut im = vec![0.0; size];
+
+		for (harmonic, (a, b)) in spectrum.iter().enumerate().take(level_harmonics + 1) {
+			re[harmonic] = *a;
+			im[harmonic] = *b;
+
+			if harmonic > 0 {
+				re[size - harmonic] = *a;
+				im[size - harmonic] = -*b;
+			}
+		}
+
+		fft(&mut re, &mut im, true);
+
+		re.iter().map(|sample| *sample as f32).collect()
+	}).collect()
+}
+
+// The complex amplitude of the first harmonics of one cycle, scaled so adding them back up gives the cycle
+fn get_harmonics(table: &[f32], num_harmonics: usize) -> Vec<(f64, f64)> {
+	let len = table.len();
+
+	if len.is_power_of_two() {
+		let mut re: Vec<f64> = table.iter().map(|sample| f64::from(*sample)).collect();
+		let mut im = vec![0.0; len];
+
+		fft(&mut re, &mut im, false);
+
+		(0..=num_harmonics).map(|harmonic| (re[harmonic] / len as f64, im[harmonic] / len as f64)).collect()
+	} else {
+		// other lengths are rare enough that a plain DFT of the harmonics that are kept will do
+		(0..=num_harmonics).map(|harmonic| {
+			table.iter().enumerate().fold((0.0, 0.0), |(a, b), (i, sample)| {
+				let angle = -TAU * (harmonic * i) as f64 / len as f64;
+				(a + f64::from(*sample) * angle.cos(), b + f64::from(*sample) * angle.sin())
+			})
+		}).map(|(a, b)| (a / len as f64, b / len as f64)).collect()
+	}
+}
+
+// In-place radix-2 FFT, for lengths that are a power of two. The inverse isn't scaled.
+pub(crate) fn fft(re: &mut [f64], im: &mut [f64], inverse: bool){
+	let len = re.len();
+
+	let mut j = 0;
+	for i in 1..len {
+		let mut bit = len >> 1;
+		while j & bit != 0 {
+			j ^= bit;
+			bit >>= 1;
+		}
+		j |= bit;
+
+		if i < j {
+			re.swap(i, j);
+			im.swap(i, j);
+		}
+	}
+
+	let sign = if inverse { 1.0 } else { -1.0 };
+	let mut span = 2;
+
+	while span <= len {
+		let half = span / 2;
+
+		for start in (0..len).step_by(span) {
+			for k in 0..half {
+				let angle = sign * TAU * k as f64 / span as f64;
+				let (w_re, w_im) = (angle.cos(), angle.sin());
+
+				let (odd_re, odd_im) = (re[start + k + half], im[start + k + half]);
+				let (t_re, t_im) = (odd_re * w_re - odd_im * w_im, odd_re * w_im + odd_im * w_re);
+
+				re[start + k + half] = re[start + k] - t_re;
+				im[start + k + half] = im[start + k] - t_im;
+				re[start + k] += t_re;
+				im[start + k] += t_im;
+			}
+		}
+
+		span *= 2;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::behavior::tests::{alias_ratio, max_step};
+
+	const SAMPLE_RATE: f32 = 44100.0;
+	const BLOCK_SIZE: usize = 256;
+
+	fn table(len: usize, f: impl Fn(f32) -> f32) -> Vec<f32> {
+		(0..len).map(|i| f(i as f32 / len as f32)).collect()
+	}
+
+	// Plays the node with the given frequency and position on every sample
+	fn render(node: &mut WavetableNode, freqs: &[f32], positions: &[f32]) -> Vec<f32> {
+		node.prepare(&ProcessContext::new(SAMPLE_RATE, BLOCK_SIZE));
+
+		let mut ins: Vec<NodeIn> = (0..3).map(|_| NodeIn::new(Vec::new(), BLOCK_SIZE)).collect();
+		let mut outs: Vec<NodeOut> = (0..2).map(|_| NodeOut { buffer: vec![0.0; BLOCK_SIZE], buffer_idx: 0 }).collect();
+		let mut samples = Vec::with_capacity(freqs.len());
+
+		for start in (0..freqs.len()).step_by(BLOCK_SIZE) {
+			let num_frames = BLOCK_SIZE.min(freqs.len() - start);
+
+			ins[FREQUENCY_INPUT].buffer[..num_frames].copy_from_slice(&freqs[start..start + num_frames]);
+			ins[POSITION_INPUT].buffer[..num_frames].copy_from_slice(&positions[start..start + num_frames]);
+
+			node.update(&ins, &mut outs, num_frames);
+			samples.extend_from_slice(&outs[OUTPUT].buffer[..num_frames]);
+		}
+
+		samples
+	}
+
+	#[test]
+	fn high_notes_read_levels_without_aliasing() {
+		let saw = table(2048, |x| 2.0 * x - 1.0);
+		let mut node = WavetableNode::new(vec![saw]);
+		node.prepare(&ProcessContext::new(SAMPLE_RATE, BLOCK_SIZE));
+
+		for freq in [440.0, 2489.0, 5012.3] {
+			// the level played has no harmonics left above nyquist
+			let level = &node.tables[0][node.get_mip_level(freq)];
+			let mut re: Vec<f64> = level.iter().map(|sample| f64::from(*sample)).collect();
+			let mut im = vec![0.0; level.len()];
+			fft(&mut re, &mut im, false);
+
+			let first_aliased = (SAMPLE_RATE / 2.0 / freq).floor() as usize + 1;
+			let aliased = (first_aliased..level.len() / 2).map(|harmonic| re[harmonic].hypot(im[harmonic])).fold(0.0, f64::max);
+			// the samples are only rounded to single precision, so 100 dB below the fundamental is nothing
+			assert!(aliased < 1e-5 * re[1].hypot(im[1]), "the level for {} Hz has a harmonic above nyquist at {}", freq, aliased);
+
+			// and what linear interpolation adds stays well down, where reading the table as is comes out at -8 to -20 dB
+			let samples = render(&mut node, &vec![freq; 10000], &vec![0.0; 10000]);
+			let ratio = alias_ratio(&samples, freq);
+			assert!(ratio < -45.0, "{} Hz aliases at {:.1} dB", freq, ratio);
+		}
+	}
+
+	#[test]
+	fn morphing_crossfades_smoothly() {
+		const LEN: usize = 44100;
+
+		let tables = vec![
+			table(2048, |x| (TAU as f32 * x).sin()),
+			table(2048, |x| (3.0 * TAU as f32 * x).sin()),
+			table(2048, |x| 0.5 * (2.0 * TAU as f32 * x).cos())
+		];
+		let freqs = vec![110.0; LEN];
+		let play = |position: f32| render(&mut WavetableNode::new(tables.clone()), &freqs, &vec![position; LEN]);
+
+		// a quarter of the way through three tables is halfway between the first two
+		let (first, second, between) = (play(0.0), play(0.5), play(0.25));
+		for ((a, b), mixed) in first.iter().zip(second.iter()).zip(between.iter()) {
+			assert!((mixed - (a + b) / 2.0).abs() < 1e-6);
+		}
+
+		// going through all of them, every step is no bigger than the steps of the tables themselves,
+		// plus how far the position moves the level between tables
+		let sweep: Vec<f32> = (0..LEN).map(|n| n as f32 / LEN as f32).collect();
+		let swept = render(&mut WavetableNode::new(tables.clone()), &freqs, &sweep);
+		let limit = [0.0, 0.5, 1.0].iter().map(|position| max_step(&play(*position))).fold(0.0, f32::max) + 2.0 * 2.0 / LEN as f32;
+
+		assert!(max_step(&swept) <= limit, "the sweep steps by {}, the tables by up to {}", max_step(&swept), limit);
+	}
+}
\ No newline at end of file
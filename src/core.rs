@@ -1,4 +1,17 @@
-pub mod heaped;
+pub mod arena;
 pub mod node;
+pub mod parameter;
+pub mod smoothing;
 pub mod node_graph;
-pub mod audio;
\ No newline at end of file
+pub mod plan;
+pub mod patch;
+pub mod renderer;
+pub mod workers;
+pub mod channel_layout;
+pub mod audio;
+pub mod offline;
+pub mod input;
+pub mod stats;
+pub mod profile;
+pub mod wav;
+pub mod error;
\ No newline at end of file
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+// An index into an Arena. The generation is bumped every time a slot is freed,
+// so ids that outlive the value they pointed to are detected instead of aliasing a newer value.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct ArenaId {
+	pub index: usize,
+	pub generation: u32
+}
+
+struct ArenaSlot<T> {
+	generation: u32,
+	value: Option<T>
+}
+
+pub struct Arena<T> {
+	slots: Vec<ArenaSlot<T>>,
+	free_indices: Vec<usize>
+}
+
+impl<T> Arena<T> {
+	pub fn new() -> Arena<T> {
+		Arena {
+			slots: Vec::new(),
+			free_indices: Vec::new()
+		}
+	}
+
+	pub fn insert(&mut self, value: T) -> ArenaId {
+		self.insert_with(|_| value)
+	}
+
+	// Inserts a value that needs to know its own id when it is created
+	pub fn insert_with<F: FnOnce(ArenaId) -> T>(&mut self, create: F) -> ArenaId {
+		let index = match self.free_indices.pop() {
+			Some(index) => index,
+			None => {
+				self.slots.push(ArenaSlot { generation: 0, value: None });
+				self.slots.len() - 1
+			}
+		};
+
+		let slot = &mut self.slots[index];
+		let id = ArenaId { index, generation: slot.generation };
+
+		slot.value = Some(create(id));
+
+		id
+	}
+
+	pub fn remove(&mut self, id: ArenaId) -> Option<T> {
+		let slot = self.slots.get_mut(id.index)?;
+
+		if slot.generation != id.generation {
+			return None;
+		}
+
+		let value = slot.value.take()?;
+
+		slot.generation = slot.generation.wrapping_add(1);
+		self.free_indices.push(id.index);
+
+		Some(value)
+	}
+
+	pub fn get(&self, id: ArenaId) -> Option<&T> {
+		match self.slots.get(id.index) {
+			Some(slot) if slot.generation == id.generation => slot.value.as_ref(),
+			_ => None
+		}
+	}
+
+	pub fn get_mut(&mut self, id: ArenaId) -> Option<&mut T> {
+		match self.slots.get_mut(id.index) {
+			Some(slot) if slot.generation == id.generation => slot.value.as_mut(),
+			_ => None
+		}
+	}
+
+	pub fn contains(&self, id: ArenaId) -> bool {
+		self.get(id).is_some()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (ArenaId, &T)> {
+		self.slots.iter().enumerate().filter_map(|(index, slot)| {
+			slot.value.as_ref().map(|value| (ArenaId { index, generation: slot.generation }, value))
+		})
+	}
+}
+
+impl<T> Default for Arena<T> {
+	fn default() -> Arena<T> {
+		Arena::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stale_ids_miss() {
+		let mut arena = Arena::new();
+		let id = arena.insert("first");
+
+		assert_eq!(arena.remove(id), Some("first"));
+		assert_eq!(arena.get(id), None);
+		assert!(!arena.contains(id));
+		assert_eq!(arena.remove(id), None);
+	}
+
+	#[test]
+	fn reused_slots_get_a_new_generation() {
+		let mut arena = Arena::new();
+		let first = arena.insert("first");
+		arena.remove(first);
+
+		let second = arena.insert("second");
+
+		assert_eq!(second.index, first.index);
+		assert_eq!(second.generation, first.generation + 1);
+
+		// the old id doesn't reach the new value, and can't remove it
+		assert_eq!(arena.get(first), None);
+		assert_eq!(arena.remove(first), None);
+		assert_eq!(arena.get(second), Some(&"second"));
+	}
+
+	#[test]
+	fn removed_slots_are_freed() {
+		let mut arena = Arena::new();
+		let ids: Vec<ArenaId> = (0..4).map(|i| arena.insert(i)).collect();
+
+		arena.remove(ids[1]);
+		arena.remove(ids[2]);
+		assert_eq!(arena.iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![0, 3]);
+
+		// new values go into the freed slots before the arena grows
+		let reused: Vec<usize> = (4..6).map(|i| arena.insert(i).index).collect();
+		assert!(reused.contains(&1) && reused.contains(&2));
+		assert_eq!(arena.insert(6).index, 4);
+	}
+}
\ No newline at end of file
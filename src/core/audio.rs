@@ -1,101 +1,764 @@
 extern crate cpal;
 use cpal::traits::{DeviceTrait, HostTrait};
-use crate::core::node::BUFFER_SIZE;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::core::node::{DEFAULT_BLOCK_SIZE, DEFAULT_SAMPLE_RATE};
+use crate::core::error::AudioError;
+use crate::core::input::{FileInput, INPUT_LATENCY_BLOCKS};
+use crate::core::channel_layout::{ChannelLayout, MAX_CHANNELS};
+use crate::core::stats::{EngineStats, SharedEngineStats};
 
 extern crate ringbuf;
 
+// Largest number of frames converted in one go, when the device doesn't tell the buffer size up front
+const MAX_CALLBACK_FRAMES: usize = 8192;
+
+// The null backend plays into nothing, for machines without a sound card
+pub const NULL_HOST_NAME: &str = "Null";
+pub const NULL_DEVICE_NAME: &str = "Null output";
+
+// Input read from a WAV file instead of a device reports this as its host, with the path as the device name
+pub const FILE_HOST_NAME: &str = "File";
+
+// Rates that are checked against the supported ranges when listing devices
+const COMMON_SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+
+// The ring buffer the graph thread renders into. It outlives the streams reading from it,
+// so the output device can be switched without touching the graph thread.
+type SharedConsumer = Arc<Mutex<ringbuf::Consumer<f32>>>;
+
+// The same for captured audio, so the input stream can be rebuilt when the sample rate changes
+type SharedProducer = Arc<Mutex<ringbuf::Producer<f32>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OutputSampleFormat {
+	F32,
+	I16,
+	U16
+}
+
+// The stream configuration picked for the output device
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OutputConfig {
+	pub host_name: String,
+	pub device_name: String,
+	pub channels: u16,
+	pub sample_rate: u32,
+	pub sample_format: OutputSampleFormat,
+	// frames per callback, none when the device doesn't say which sizes it supports
+	pub buffer_size: Option<u32>
+}
+
+impl OutputConfig {
+	fn stream_config(&self) -> cpal::StreamConfig {
+		cpal::StreamConfig {
+			channels: self.channels,
+			sample_rate: cpal::SampleRate(self.sample_rate),
+			buffer_size: match self.buffer_size {
+				Some(buffer_size) => cpal::BufferSize::Fixed(buffer_size),
+				None => cpal::BufferSize::Default
+			}
+		}
+	}
+}
+
+// The stream configuration picked for the input, which always runs at the output's sample rate
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct InputConfig {
+	pub host_name: String,
+	pub device_name: String,
+	pub channels: u16,
+	pub sample_rate: u32,
+	pub sample_format: OutputSampleFormat,
+	pub buffer_size: Option<u32>,
+	// frames from a sample being captured to the graph reading it, on top of the output latency
+	pub latency_frames: usize
+}
+
+impl InputConfig {
+	fn stream_config(&self) -> cpal::StreamConfig {
+		cpal::StreamConfig {
+			channels: self.channels,
+			sample_rate: cpal::SampleRate(self.sample_rate),
+			buffer_size: match self.buffer_size {
+				Some(buffer_size) => cpal::BufferSize::Fixed(buffer_size),
+				None => cpal::BufferSize::Default
+			}
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DeviceInfo {
+	pub host_name: String,
+	pub device_name: String,
+	pub max_channels: u16,
+	pub sample_rates: Vec<u32>,
+	pub is_selected: bool
+}
+
+enum OutputDevice {
+	Device { host_id: cpal::HostId, device: cpal::Device },
+	Null
+}
+
+// Never read, a stream plays for as long as it is kept alive
+#[allow(dead_code)]
+enum OutputStream {
+	Device(cpal::Stream),
+	Null(PacedThread)
+}
+
+// Never read either
+#[allow(dead_code)]
+enum InputStream {
+	Device(cpal::Stream),
+	File(PacedThread)
+}
+
 pub struct AudioManager {
-	host: cpal::Host,
-	output_device: cpal::Device,
-	output_stream: Option<cpal::Stream>
+	// devices are asked for callbacks of half a block
+	block_size: usize,
+	// what the graph renders, interleaved, which is mixed to whatever the device has
+	channel_layout: ChannelLayout,
+	// recorded by every stream, and by whoever else is handed a clone
+	stats: SharedEngineStats,
+
+	output_device: OutputDevice,
+	output_stream: Option<OutputStream>,
+	output_config: Option<OutputConfig>,
+
+	// set once the output stream is opened, and reused by every stream after that
+	ringbuf_consumer: Option<SharedConsumer>,
+	generator_thread: Option<thread::Thread>,
+
+	input_device: Option<cpal::Device>,
+	input_stream: Option<InputStream>,
+	input_config: Option<InputConfig>,
+	input_producer: Option<SharedProducer>
 }
 
 impl AudioManager {
-	pub fn new() -> AudioManager {
+	// Uses the default output device, or the null backend if there isn't a usable one.
+	// Without a channel layout, the engine renders as many channels as the device has by default.
+	pub fn new(block_size: usize, channel_layout: Option<ChannelLayout>) -> AudioManager {
 		let host = cpal::default_host();
 		println!("Instantiated audio host with id {:?}", host.id());
 
-		let output_device = host.default_output_device().expect("No output device available!");
+		// some hosts hand out a default device even when there is no sound card, which then fails to report any config
+		let output_device = match host.default_output_device() {
+			Some(device) if device.default_output_config().is_ok() => OutputDevice::Device { host_id: host.id(), device },
+			_ => {
+				println!("No output device available, playing into the null backend instead");
+				OutputDevice::Null
+			}
+		};
+
+		let input_device = host.default_input_device().filter(|device| device.default_input_config().is_ok());
+
+		let channel_layout = channel_layout.unwrap_or_else(|| match &output_device {
+			OutputDevice::Device { device, .. } => match device.default_output_config() {
+				Ok(config) => ChannelLayout::from_channels(config.channels()),
+				Err(_) => ChannelLayout::default()
+			},
+			OutputDevice::Null => ChannelLayout::default()
+		});
 
 		AudioManager {
-			host: host,
-			output_device: output_device,
-			output_stream: None
+			block_size,
+			channel_layout,
+			stats: EngineStats::new(),
+
+			output_device,
+			output_stream: None,
+			output_config: None,
+
+			ringbuf_consumer: None,
+			generator_thread: None,
+
+			input_device,
+			input_stream: None,
+			input_config: None,
+			input_producer: None
+		}
+	}
+
+	pub fn get_channel_layout(&self) -> ChannelLayout {
+		self.channel_layout
+	}
+
+	pub fn get_stats(&self) -> SharedEngineStats {
+		self.stats.clone()
+	}
+
+	// The configuration of the open output stream
+	pub fn get_output_config(&self) -> Option<&OutputConfig> {
+		self.output_config.as_ref()
+	}
+
+	// The configuration of the open input stream
+	pub fn get_input_config(&self) -> Option<&InputConfig> {
+		self.input_config.as_ref()
+	}
+
+	// Every output device of every available host, plus the null backend
+	pub fn list_devices(&self) -> Vec<DeviceInfo> {
+		let mut devices = Vec::new();
+
+		for host_id in cpal::available_hosts() {
+			let host = match cpal::host_from_id(host_id) {
+				Ok(host) => host,
+				Err(_) => continue
+			};
+
+			let output_devices = match host.output_devices() {
+				Ok(output_devices) => output_devices,
+				Err(e) => {
+					println!("Couldn't list the output devices of host {}: {}", host_id.name(), e);
+					continue;
+				}
+			};
+
+			for device in output_devices {
+				let device_name = match device.name() {
+					Ok(device_name) => device_name,
+					Err(_) => continue
+				};
+
+				let ranges: Vec<cpal::SupportedStreamConfigRange> = match device.supported_output_configs() {
+					Ok(ranges) => ranges.collect(),
+					Err(_) => Vec::new()
+				};
+
+				let sample_rates = COMMON_SAMPLE_RATES.iter().copied().filter(|sample_rate| {
+					ranges.iter().any(|range| (range.min_sample_rate().0..=range.max_sample_rate().0).contains(sample_rate))
+				}).collect();
+
+				devices.push(DeviceInfo {
+					host_name: host_id.name().to_string(),
+					is_selected: self.is_selected(host_id.name(), &device_name),
+					device_name,
+					max_channels: ranges.iter().map(|range| range.channels()).max().unwrap_or(0),
+					sample_rates
+				});
+			}
 		}
+
+		devices.push(DeviceInfo {
+			host_name: NULL_HOST_NAME.to_string(),
+			device_name: NULL_DEVICE_NAME.to_string(),
+			max_channels: MAX_CHANNELS,
+			sample_rates: COMMON_SAMPLE_RATES.to_vec(),
+			is_selected: matches!(self.output_device, OutputDevice::Null)
+		});
+
+		devices
 	}
 
-	pub fn open_output_stream(&mut self, mut ringbuf_consumer: ringbuf::Consumer::<f32>, generator_thread: std::thread::Thread) {
-		let supported_config = self.output_device.default_output_config().expect("Default output config not found?");
-		println!("Default output config: {:?}", supported_config);
+	fn is_selected(&self, host_name: &str, device_name: &str) -> bool {
+		match &self.output_config {
+			Some(config) => config.host_name == host_name && config.device_name == device_name,
+			None => false
+		}
+	}
+
+	// Picks the supported configuration closest to what the graph renders: the channels of the layout, as f32 at the preferred sample rate
+	// (or the device's default), with buffers of half a block. Anything else is converted in the stream callback.
+	pub fn negotiate_output_config(&self, preferred_sample_rate: Option<u32>) -> Result<OutputConfig, AudioError> {
+		let desired_buffer_size = (self.block_size / 2) as u32;
+
+		let (host_id, device) = match &self.output_device {
+			OutputDevice::Device { host_id, device } => (host_id, device),
+			OutputDevice::Null => {
+				return Ok(OutputConfig {
+					host_name: NULL_HOST_NAME.to_string(),
+					device_name: NULL_DEVICE_NAME.to_string(),
+					channels: self.channel_layout.channels() as u16,
+					sample_rate: preferred_sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE as u32),
+					sample_format: OutputSampleFormat::F32,
+					buffer_size: Some(desired_buffer_size)
+				});
+			}
+		};
+
+		let device_name = device.name().unwrap_or_else(|_| String::from("unknown device"));
+
+		let desired_sample_rate = match preferred_sample_rate {
+			Some(sample_rate) => sample_rate,
+			None => device.default_output_config().map_err(|e| AudioError::ConfigUnavailable(e.to_string()))?.sample_rate().0
+		};
 
-		let supported_channels = supported_config.channels();
-		let desired_channels: u16 = 2;
+		let supported_configs = device.supported_output_configs().map_err(|e| AudioError::ConfigUnavailable(e.to_string()))?;
 
-		let supported_sample_rate = supported_config.sample_rate();
-		let desired_sample_rate: u32 = 44100;
+		let best = supported_configs.filter_map(|range| {
+			if range.channels() == 0 {
+				return None;
+			}
+
+			let sample_format = match range.sample_format() {
+				cpal::SampleFormat::F32 => OutputSampleFormat::F32,
+				cpal::SampleFormat::I16 => OutputSampleFormat::I16,
+				cpal::SampleFormat::U16 => OutputSampleFormat::U16
+			};
+
+			let sample_rate = desired_sample_rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
 
-		let supported_sample_format = supported_config.sample_format();
-		let desired_sample_format = cpal::SampleFormat::F32;
+			let buffer_size = match range.buffer_size() {
+				cpal::SupportedBufferSize::Range { min, max } => Some(desired_buffer_size.clamp(*min, (*max).max(*min))),
+				cpal::SupportedBufferSize::Unknown => None
+			};
 
-		let supported_buffer_size = supported_config.buffer_size();
-		let desired_buffer_size: u32 = (BUFFER_SIZE / 2).try_into().unwrap();
+			let config = OutputConfig {
+				host_name: host_id.name().to_string(),
+				device_name: device_name.clone(),
+				channels: range.channels(),
+				sample_rate,
+				sample_format,
+				buffer_size
+			};
 
-		// the final number of samples to generate per stream call
-		let output_size: usize = (desired_buffer_size * u32::from(desired_channels)).try_into().unwrap();
+			// lower is better, in order of importance
+			let score = (
+				channel_penalty(config.channels, self.channel_layout.channels()),
+				sample_rate.abs_diff(desired_sample_rate),
+				sample_format_penalty(sample_format),
+				buffer_size.map_or(u32::MAX, |buffer_size| buffer_size.abs_diff(desired_buffer_size))
+			);
 
-		if supported_channels!= desired_channels {
-			panic!("Output stream should have {} channels, but the default config instead has {} channels!", desired_channels, supported_channels);
+			Some((score, config))
+		}).min_by_key(|(score, _)| *score);
+
+		match best {
+			Some((_, config)) => Ok(config),
+			None => Err(AudioError::NoSupportedConfig(device_name))
 		}
+	}
+
+	pub fn open_output_stream(&mut self, config: &OutputConfig, ringbuf_consumer: ringbuf::Consumer::<f32>, generator_thread: std::thread::Thread) -> Result<(), AudioError> {
+		self.ringbuf_consumer = Some(Arc::new(Mutex::new(ringbuf_consumer)));
+		self.generator_thread = Some(generator_thread);
 
-		if supported_sample_rate.0 != desired_sample_rate {
-			panic!("Output stream should have a sample rate of {} Hz, but the default config instead has a sample of {} Hz!", desired_sample_rate, supported_sample_rate.0);
+		let stream = self.build_stream(config)?;
+
+		self.output_stream = Some(stream);
+		self.output_config = Some(config.clone());
+
+		Ok(())
+	}
+
+	// Switches to another output device, keeping the current sample rate if the device supports it.
+	// A failed switch keeps the old device playing.
+	pub fn select_output_device(&mut self, host_name: &str, device_name: &str) -> Result<OutputConfig, AudioError> {
+		let output_device = find_output_device(host_name, device_name)?;
+		let previous_device = std::mem::replace(&mut self.output_device, output_device);
+
+		let result = self.reopen_output();
+
+		if result.is_err() {
+			self.output_device = previous_device;
+		}
+
+		result
+	}
+
+	// Reopens the output with buffers to match the new block size. The input stream keeps its buffers,
+	// only its latency changes, since that is counted in blocks.
+	pub fn set_block_size(&mut self, block_size: usize) -> Result<OutputConfig, AudioError> {
+		let previous_block_size = std::mem::replace(&mut self.block_size, block_size);
+
+		let result = self.reopen_output();
+
+		if result.is_err() {
+			self.block_size = previous_block_size;
 		}
 
-		if supported_sample_format != desired_sample_format {
-			panic!("Output stream should have a sample format of type {:?}, but the default config instead has a format of type {:?}!", desired_sample_format, supported_sample_format);
+		if let Some(input_config) = self.input_config.as_mut() {
+			input_config.latency_frames = (INPUT_LATENCY_BLOCKS + 1) * block_size;
+		}
+
+		result
+	}
+
+	// Builds a new stream for the current device, keeping the current sample rate if the device supports it.
+	// The new stream is built before the old one is dropped, so the old one keeps playing if that fails.
+	fn reopen_output(&mut self) -> Result<OutputConfig, AudioError> {
+		let preferred_sample_rate = self.output_config.as_ref().map(|config| config.sample_rate);
+
+		let config = self.negotiate_output_config(preferred_sample_rate)?;
+		let stream = self.build_stream(&config)?;
+
+		self.output_stream = Some(stream);
+		self.output_config = Some(config.clone());
+
+		if preferred_sample_rate != Some(config.sample_rate) {
+			self.follow_sample_rate(config.sample_rate);
+		}
+
+		Ok(config)
+	}
+
+	fn build_stream(&self, config: &OutputConfig) -> Result<OutputStream, AudioError> {
+		println!("Opening output stream with {:?}", config);
+
+		let ringbuf_consumer = self.ringbuf_consumer.clone().expect("Trying to build an output stream before it was opened!");
+		let generator_thread = self.generator_thread.clone().expect("Trying to build an output stream before it was opened!");
+
+		let engine_channels = self.channel_layout.channels();
+
+		let device = match &self.output_device {
+			OutputDevice::Device { device, .. } => device,
+			OutputDevice::Null => return Ok(OutputStream::Null(start_null_stream(config, engine_channels, ringbuf_consumer, generator_thread, self.stats.clone())))
+		};
+
+		let stream_config = config.stream_config();
+		let channels = (engine_channels, usize::from(config.channels));
+
+		let stream = match config.sample_format {
+			OutputSampleFormat::F32 => build_output_stream::<f32>(device, &stream_config, channels, ringbuf_consumer, generator_thread, self.stats.clone()),
+			OutputSampleFormat::I16 => build_output_stream::<i16>(device, &stream_config, channels, ringbuf_consumer, generator_thread, self.stats.clone()),
+			OutputSampleFormat::U16 => build_output_stream::<u16>(device, &stream_config, channels, ringbuf_consumer, generator_thread, self.stats.clone())
+		}.map_err(|e| AudioError::StreamFailed(e.to_string()))?;
+
+		Ok(OutputStream::Device(stream))
+	}
+
+	// Picks the input configuration closest to what the graph reads, which has to be at the output's sample rate
+	// since the input isn't resampled
+	pub fn negotiate_input_config(&self, sample_rate: u32) -> Result<InputConfig, AudioError> {
+		let desired_buffer_size = (self.block_size / 2) as u32;
+
+		let device = self.input_device.as_ref().ok_or(AudioError::NoInputDevice)?;
+		let device_name = device.name().unwrap_or_else(|_| String::from("unknown device"));
+		// the input device always comes from the default host
+		let host_name = cpal::default_host().id().name().to_string();
+
+		let supported_configs = device.supported_input_configs().map_err(|e| AudioError::ConfigUnavailable(e.to_string()))?;
+
+		let best = supported_configs.filter_map(|range| {
+			if range.channels() == 0 || !(range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate) {
+				return None;
+			}
+
+			let sample_format = match range.sample_format() {
+				cpal::SampleFormat::F32 => OutputSampleFormat::F32,
+				cpal::SampleFormat::I16 => OutputSampleFormat::I16,
+				cpal::SampleFormat::U16 => OutputSampleFormat::U16
+			};
+
+			let buffer_size = match range.buffer_size() {
+				cpal::SupportedBufferSize::Range { min, max } => Some(desired_buffer_size.clamp(*min, (*max).max(*min))),
+				cpal::SupportedBufferSize::Unknown => None
+			};
+
+			let config = InputConfig {
+				host_name: host_name.clone(),
+				device_name: device_name.clone(),
+				channels: range.channels(),
+				sample_rate,
+				sample_format,
+				buffer_size,
+				latency_frames: (INPUT_LATENCY_BLOCKS + 1) * self.block_size
+			};
+
+			// every captured channel becomes an output of the input nodes, so the closest to the layout is preferred
+			let score = (
+				usize::from(config.channels).abs_diff(self.channel_layout.channels()),
+				sample_format_penalty(sample_format),
+				buffer_size.map_or(u32::MAX, |buffer_size| buffer_size.abs_diff(desired_buffer_size))
+			);
+
+			Some((score, config))
+		}).min_by_key(|(score, _)| *score);
+
+		match best {
+			Some((_, config)) => Ok(config),
+			None => Err(AudioError::NoSupportedConfig(device_name))
 		}
+	}
+
+	// Starts capturing from the input device into the ring buffer the graph thread reads from
+	pub fn open_input_stream(&mut self, config: &InputConfig, ringbuf_producer: ringbuf::Producer::<f32>) -> Result<(), AudioError> {
+		let ringbuf_producer = Arc::new(Mutex::new(ringbuf_producer));
+		let stream = self.build_input_stream(config, ringbuf_producer.clone())?;
+
+		self.input_producer = Some(ringbuf_producer);
+		self.input_stream = Some(stream);
+		self.input_config = Some(config.clone());
+
+		Ok(())
+	}
+
+	// Feeds a WAV file into the ring buffer at the pace of an input device, for running without one
+	pub fn open_file_input(&mut self, path: &str, mut file_input: FileInput, sample_rate: u32, mut ringbuf_producer: ringbuf::Producer::<f32>) -> InputConfig {
+		let config = InputConfig {
+			host_name: FILE_HOST_NAME.to_string(),
+			device_name: path.to_string(),
+			channels: file_input.channels() as u16,
+			sample_rate,
+			sample_format: OutputSampleFormat::F32,
+			buffer_size: Some((self.block_size / 2) as u32),
+			latency_frames: (INPUT_LATENCY_BLOCKS + 1) * self.block_size
+		};
+
+		let num_frames = self.block_size / 2;
+		let period = Duration::from_secs_f64(num_frames as f64 / f64::from(sample_rate));
+		let mut samples = vec![0.0; num_frames * file_input.channels()];
+		let stats = self.stats.clone();
+
+		println!("Opening file input with {:?}", config);
 
-		match supported_buffer_size {
-			cpal::SupportedBufferSize::Unknown => {
-				panic!("Output stream should have a buffer size of {}, but the default config has a unknown buffer size", desired_buffer_size);
+		self.input_stream = Some(InputStream::File(PacedThread::start(period, move || {
+			file_input.read(&mut samples);
+
+			if ringbuf_producer.push_slice(&samples) < samples.len() {
+				stats.record_overrun();
+			}
+		})));
+		self.input_config = Some(config.clone());
+
+		config
+	}
+
+	// Rebuilds the device input at the new sample rate of the output. A file keeps playing at its own rate.
+	fn follow_sample_rate(&mut self, sample_rate: u32){
+		let ringbuf_producer = match (&self.input_config, &self.input_producer) {
+			(Some(config), Some(ringbuf_producer)) if config.host_name != FILE_HOST_NAME => ringbuf_producer.clone(),
+			(Some(config), _) => {
+				println!("Input {} stays at {} Hz", config.device_name, config.sample_rate);
+				return;
 			},
-			cpal::SupportedBufferSize::Range { min, max } => {
-				if &desired_buffer_size < min || &desired_buffer_size > max {
-					panic!("Output stream should have a buffer size of {}, but the default config wants a buffer between {} and {}", desired_buffer_size, min, max);
-				}
+			_ => return
+		};
+
+		// the old stream has to stop pushing before the new one starts
+		self.input_stream = None;
+
+		let result = self.negotiate_input_config(sample_rate).and_then(|config| {
+			let stream = self.build_input_stream(&config, ringbuf_producer)?;
+
+			Ok((stream, config))
+		});
+
+		match result {
+			Ok((stream, config)) => {
+				self.input_stream = Some(stream);
+				self.input_config = Some(config);
+			},
+			Err(e) => {
+				println!("Couldn't reopen the input at {} Hz, the input nodes stay silent: {}", sample_rate, e);
+				self.input_config = None;
 			}
 		}
+	}
+
+	fn build_input_stream(&self, config: &InputConfig, ringbuf_producer: SharedProducer) -> Result<InputStream, AudioError> {
+		println!("Opening input stream with {:?}", config);
+
+		let device = self.input_device.as_ref().ok_or(AudioError::NoInputDevice)?;
+		let stream_config = config.stream_config();
+
+		let stream = match config.sample_format {
+			OutputSampleFormat::F32 => build_input_stream::<f32>(device, &stream_config, ringbuf_producer, self.stats.clone()),
+			OutputSampleFormat::I16 => build_input_stream::<i16>(device, &stream_config, ringbuf_producer, self.stats.clone()),
+			OutputSampleFormat::U16 => build_input_stream::<u16>(device, &stream_config, ringbuf_producer, self.stats.clone())
+		}.map_err(|e| AudioError::StreamFailed(e.to_string()))?;
+
+		Ok(InputStream::Device(stream))
+	}
+}
+
+fn find_output_device(host_name: &str, device_name: &str) -> Result<OutputDevice, AudioError> {
+	if host_name == NULL_HOST_NAME {
+		return Ok(OutputDevice::Null);
+	}
+
+	let not_found = || AudioError::DeviceNotFound { host_name: host_name.to_string(), device_name: device_name.to_string() };
+
+	let host_id = cpal::available_hosts().into_iter().find(|host_id| host_id.name() == host_name).ok_or_else(not_found)?;
+	let host = cpal::host_from_id(host_id).map_err(|_| not_found())?;
+
+	let device = host.output_devices().map_err(|_| not_found())?.find(|device| {
+		device.name().map(|name| name == device_name).unwrap_or(false)
+	}).ok_or_else(not_found)?;
 
-		let mut config = supported_config.config();
+	Ok(OutputDevice::Device { host_id, device })
+}
+
+fn channel_penalty(channels: u16, engine_channels: usize) -> u16 {
+	match usize::from(channels) {
+		n if n == engine_channels => 0,
+		// more channels only means some stay silent
+		n if n > engine_channels => channels,
+		// fewer channels means some get mixed down or dropped
+		_ => u16::MAX - channels
+	}
+}
+
+fn sample_format_penalty(sample_format: OutputSampleFormat) -> u8 {
+	match sample_format {
+		OutputSampleFormat::F32 => 0,
+		OutputSampleFormat::I16 => 1,
+		OutputSampleFormat::U16 => 2
+	}
+}
 
-		config.buffer_size = cpal::BufferSize::Fixed(desired_buffer_size);
+// channels is the number of channels in the ring buffer, followed by the number of channels of the device
+fn build_output_stream<T: cpal::Sample>(device: &cpal::Device, config: &cpal::StreamConfig, (engine_channels, channels): (usize, usize), ringbuf_consumer: SharedConsumer, generator_thread: std::thread::Thread, stats: SharedEngineStats) -> Result<cpal::Stream, cpal::BuildStreamError> {
+	let error_fn = |err| eprintln!("Error building output sound stream: {}", err);
 
-		let error_fn = |err| eprintln!("Error building output sound stream: {}", err);
+	// samples are taken out of the ring buffer in here before they're converted, so the callback never allocates
+	let mut engine_samples = vec![0.0; MAX_CALLBACK_FRAMES * engine_channels];
+	let mut previous_callback: Option<Instant> = None;
 
-		let stream_result = self.output_device.build_output_stream(&config, move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-			if output.len() != output_size {
-				panic!("output.len() != output_size ({} != {})", output.len(), output_size);
+	device.build_output_stream(config, move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+		let started = Instant::now();
+		let interval = previous_callback.map_or(Duration::ZERO, |previous| started.saturating_duration_since(previous));
+		previous_callback = Some(started);
+
+		// the lock is only ever held by another stream for the short moment two streams overlap during a switch
+		let mut ringbuf_consumer = ringbuf_consumer.try_lock();
+		let mut is_underrun = false;
+
+		for output_chunk in output.chunks_mut(MAX_CALLBACK_FRAMES * channels) {
+			let num_frames = output_chunk.len() / channels;
+			let engine_chunk = &mut engine_samples[..num_frames * engine_channels];
+
+			match ringbuf_consumer.as_mut() {
+				Ok(ringbuf_consumer) if ringbuf_consumer.len() >= engine_chunk.len() => {
+					ringbuf_consumer.pop_slice(engine_chunk);
+				},
+				_ => {
+					is_underrun = true;
+					engine_chunk.iter_mut().for_each(|m| *m = 0.0);
+				}
 			}
 
-			if ringbuf_consumer.len() < output_size {
-				//println!("Not enough samples in ring buffer, sending zeroes instead");
-				output.iter_mut().for_each(|m| *m = 0.0);
-			} else {
-				ringbuf_consumer.pop_slice(output);
-				//println!("{} samples left in ring buffer", ringbuf_consumer.len())
+			for (frame, engine_frame) in output_chunk.chunks_mut(channels).zip(engine_chunk.chunks(engine_channels)) {
+				mix_frame(engine_frame, frame);
 			}
-			
-			generator_thread.unpark();
+		}
 
-		}, error_fn);
+		generator_thread.unpark();
 
-		match stream_result {
-			Ok(stream) => {
-				self.output_stream = Some(stream)
+		if is_underrun {
+			stats.record_underrun();
+		}
+
+		stats.record_callback(started.elapsed(), interval);
+	}, error_fn)
+}
+
+fn build_input_stream<T: cpal::Sample>(device: &cpal::Device, config: &cpal::StreamConfig, ringbuf_producer: SharedProducer, stats: SharedEngineStats) -> Result<cpal::Stream, cpal::BuildStreamError> {
+	let error_fn = |err| eprintln!("Error building input sound stream: {}", err);
+
+	device.build_input_stream(config, move |input: &[T], _: &cpal::InputCallbackInfo| {
+		// when the graph thread falls behind, whatever doesn't fit is dropped
+		let num_pushed = match ringbuf_producer.try_lock() {
+			Ok(mut ringbuf_producer) => ringbuf_producer.push_iter(&mut input.iter().map(|sample| sample.to_f32())),
+			Err(_) => 0
+		};
+
+		if num_pushed < input.len() {
+			stats.record_overrun();
+		}
+	}, error_fn)
+}
+
+// Maps a frame onto a frame with another number of channels. Mixing down to mono takes the average of every channel,
+// mono goes to the first two channels, and otherwise channels are copied in order, with the ones left over dropped
+// or kept silent.
+pub(crate) fn mix_frame<T: cpal::Sample>(source: &[f32], frame: &mut [T]){
+	match (source.len(), frame.len()) {
+		(n, m) if n == m => {
+			for (sample, source_sample) in frame.iter_mut().zip(source.iter()) {
+				*sample = T::from(source_sample);
+			}
+		},
+		(n, 1) => {
+			frame[0] = T::from(&(source.iter().sum::<f32>() / n as f32));
+		},
+		(1, _) => {
+			for (channel, sample) in frame.iter_mut().enumerate() {
+				*sample = T::from(if channel < 2 { &source[0] } else { &0.0f32 });
+			}
+		},
+		_ => {
+			for (channel, sample) in frame.iter_mut().enumerate() {
+				*sample = T::from(source.get(channel).unwrap_or(&0.0f32));
+			}
+		}
+	}
+}
+
+// Takes samples out of the ring buffer at the pace a real device would, and throws them away
+fn start_null_stream(config: &OutputConfig, engine_channels: usize, ringbuf_consumer: SharedConsumer, generator_thread: thread::Thread, stats: SharedEngineStats) -> PacedThread {
+	let num_frames = config.buffer_size.unwrap_or((DEFAULT_BLOCK_SIZE / 2) as u32) as usize;
+	let period = Duration::from_secs_f64(num_frames as f64 / f64::from(config.sample_rate));
+
+	let mut engine_samples = vec![0.0; num_frames * engine_channels];
+	let mut previous_callback: Option<Instant> = None;
+
+	PacedThread::start(period, move || {
+		let started = Instant::now();
+		let interval = previous_callback.map_or(Duration::ZERO, |previous| started.saturating_duration_since(previous));
+		previous_callback = Some(started);
+
+		let is_underrun = match ringbuf_consumer.try_lock() {
+			Ok(mut ringbuf_consumer) if ringbuf_consumer.len() >= engine_samples.len() => {
+				ringbuf_consumer.pop_slice(&mut engine_samples);
+				false
 			},
-			Err(e) => {
-				panic!("Error when opening output stream: {}", e)
+			_ => true
+		};
+
+		generator_thread.unpark();
+
+		if is_underrun {
+			stats.record_underrun();
+		}
+
+		stats.record_callback(started.elapsed(), interval);
+	})
+}
+
+// Calls the callback once every period, standing in for the callbacks of a device
+struct PacedThread {
+	is_running: Arc<AtomicBool>,
+	thread: Option<thread::JoinHandle<()>>
+}
+
+impl PacedThread {
+	fn start<F: FnMut() + Send + 'static>(period: Duration, mut callback: F) -> PacedThread {
+		let is_running = Arc::new(AtomicBool::new(true));
+		let thread_is_running = is_running.clone();
+
+		let thread = thread::spawn(move || {
+			let mut next_callback = Instant::now();
+
+			while thread_is_running.load(Ordering::Relaxed) {
+				callback();
+
+				next_callback += period;
+				thread::sleep(next_callback.saturating_duration_since(Instant::now()));
 			}
+		});
+
+		PacedThread {
+			is_running,
+			thread: Some(thread)
+		}
+	}
+}
+
+impl Drop for PacedThread {
+	fn drop(&mut self){
+		self.is_running.store(false, Ordering::Relaxed);
+
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
 		}
 	}
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+// Largest number of channels the engine renders, enough for big audio interfaces
+pub const MAX_CHANNELS: u16 = 64;
+
+// The channels the graph renders, which output nodes have one input each for.
+// Devices with another number of channels get the layout mixed onto theirs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum ChannelLayout {
+	#[serde(rename = "mono")]
+	Mono,
+	#[default]
+	#[serde(rename = "stereo")]
+	Stereo,
+	#[serde(rename = "quad")]
+	Quad,
+	#[serde(rename = "5.1")]
+	Surround51,
+	#[serde(rename = "7.1")]
+	Surround71,
+	// any other number of channels, without speaker positions
+	#[serde(rename = "discrete")]
+	Discrete(u16)
+}
+
+impl ChannelLayout {
+	pub fn from_channels(channels: u16) -> ChannelLayout {
+		match channels {
+			1 => ChannelLayout::Mono,
+			2 => ChannelLayout::Stereo,
+			4 => ChannelLayout::Quad,
+			6 => ChannelLayout::Surround51,
+			8 => ChannelLayout::Surround71,
+			n => ChannelLayout::Discrete(n.clamp(1, MAX_CHANNELS))
+		}
+	}
+
+	pub fn channels(&self) -> usize {
+		match self {
+			ChannelLayout::Mono => 1,
+			ChannelLayout::Stereo => 2,
+			ChannelLayout::Quad => 4,
+			ChannelLayout::Surround51 => 6,
+			ChannelLayout::Surround71 => 8,
+			ChannelLayout::Discrete(channels) => usize::from(*channels)
+		}
+	}
+}
+
+impl fmt::Display for ChannelLayout {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ChannelLayout::Mono => write!(f, "mono"),
+			ChannelLayout::Stereo => write!(f, "stereo"),
+			ChannelLayout::Quad => write!(f, "quad"),
+			ChannelLayout::Surround51 => write!(f, "5.1"),
+			ChannelLayout::Surround71 => write!(f, "7.1"),
+			ChannelLayout::Discrete(channels) => write!(f, "{} channels", channels)
+		}
+	}
+}
+
+// Parses the names used on the command line, or a plain number of channels
+impl FromStr for ChannelLayout {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<ChannelLayout, String> {
+		match s {
+			"mono" => Ok(ChannelLayout::Mono),
+			"stereo" => Ok(ChannelLayout::Stereo),
+			"quad" => Ok(ChannelLayout::Quad),
+			"5.1" => Ok(ChannelLayout::Surround51),
+			"7.1" => Ok(ChannelLayout::Surround71),
+			_ => match s.parse::<u16>() {
+				Ok(channels) if (1..=MAX_CHANNELS).contains(&channels) => Ok(ChannelLayout::from_channels(channels)),
+				_ => Err(format!("Unknown channel layout '{}', expected mono, stereo, quad, 5.1, 7.1 or a number of channels up to {}", s, MAX_CHANNELS))
+			}
+		}
+	}
+}
\ No newline at end of file
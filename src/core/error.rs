@@ -0,0 +1,140 @@
+use std::fmt;
+use crate::core::node::{NodeId, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use crate::core::parameter::ParameterId;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+	NodeNotFound(NodeId),
+	OutputOutOfRange { node: NodeId, output_idx: usize, num_outs: usize },
+	InputOutOfRange { node: NodeId, input_idx: usize, num_ins: usize },
+	EdgeAlreadyExists { from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize },
+	EdgeNotFound { from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize },
+	RecipeNotFound(String),
+	InvalidParameter { name: String, reason: String },
+	ParameterNotFound { node: NodeId, parameter_id: ParameterId, num_parameters: usize },
+	NotSavable(NodeId),
+	InvalidPatch(String),
+	CycleDetected(Vec<NodeId>),
+	InvalidBlockSize(usize),
+	RendererBusy
+}
+
+impl GraphError {
+	// A stable, machine readable identifier for the error, sent along to websocket clients
+	pub fn code(&self) -> &'static str {
+		match self {
+			GraphError::NodeNotFound(_) => "node_not_found",
+			GraphError::OutputOutOfRange { .. } => "output_out_of_range",
+			GraphError::InputOutOfRange { .. } => "input_out_of_range",
+			GraphError::EdgeAlreadyExists { .. } => "edge_already_exists",
+			GraphError::EdgeNotFound { .. } => "edge_not_found",
+			GraphError::RecipeNotFound(_) => "recipe_not_found",
+			GraphError::InvalidParameter { .. } => "invalid_parameter",
+			GraphError::ParameterNotFound { .. } => "parameter_not_found",
+			GraphError::NotSavable(_) => "not_savable",
+			GraphError::InvalidPatch(_) => "invalid_patch",
+			GraphError::CycleDetected(_) => "cycle_detected",
+			GraphError::InvalidBlockSize(_) => "invalid_block_size",
+			GraphError::RendererBusy => "renderer_busy"
+		}
+	}
+}
+
+impl fmt::Display for GraphError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			GraphError::NodeNotFound(node) => {
+				write!(f, "Node {} doesn't exist", node)
+			},
+			GraphError::OutputOutOfRange { node, output_idx, num_outs } => {
+				write!(f, "Node {} has {} outputs, output {} doesn't exist", node, num_outs, output_idx)
+			},
+			GraphError::InputOutOfRange { node, input_idx, num_ins } => {
+				write!(f, "Node {} has {} inputs, input {} doesn't exist", node, num_ins, input_idx)
+			},
+			GraphError::EdgeAlreadyExists { from, from_out_idx, to, to_in_idx } => {
+				write!(f, "There already is an edge from {}:{} to {}:{}", from, from_out_idx, to, to_in_idx)
+			},
+			GraphError::EdgeNotFound { from, from_out_idx, to, to_in_idx } => {
+				write!(f, "There is no edge from {}:{} to {}:{}", from, from_out_idx, to, to_in_idx)
+			},
+			GraphError::RecipeNotFound(name) => {
+				write!(f, "Couldn't find a node recipe with the name '{}'", name)
+			},
+			GraphError::InvalidParameter { name, reason } => {
+				write!(f, "Invalid value for parameter '{}': {}", name, reason)
+			},
+			GraphError::ParameterNotFound { node, parameter_id, num_parameters } => {
+				write!(f, "Node {} has {} parameters, parameter {} doesn't exist", node, num_parameters, parameter_id)
+			},
+			GraphError::NotSavable(node) => {
+				write!(f, "Node {} wasn't created from a recipe, so it can't be saved to a patch", node)
+			},
+			GraphError::InvalidPatch(reason) => {
+				write!(f, "Invalid patch: {}", reason)
+			},
+			GraphError::CycleDetected(nodes) => {
+				let names: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+				write!(f, "The connection would create a cycle through {}, use a feedback edge to close the loop", names.join(", "))
+			},
+			GraphError::InvalidBlockSize(block_size) => {
+				write!(f, "Block size {} is out of range, it has to be between {} and {} frames", block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+			},
+			GraphError::RendererBusy => {
+				write!(f, "The renderer hasn't caught up with earlier changes yet")
+			}
+		}
+	}
+}
+
+impl std::error::Error for GraphError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioError {
+	DeviceNotFound { host_name: String, device_name: String },
+	ConfigUnavailable(String),
+	NoSupportedConfig(String),
+	StreamFailed(String),
+	NoInputDevice,
+	InputFailed(String)
+}
+
+impl AudioError {
+	pub fn code(&self) -> &'static str {
+		match self {
+			AudioError::DeviceNotFound { .. } => "device_not_found",
+			AudioError::ConfigUnavailable(_) => "config_unavailable",
+			AudioError::NoSupportedConfig(_) => "no_supported_config",
+			AudioError::StreamFailed(_) => "stream_failed",
+			AudioError::NoInputDevice => "no_input_device",
+			AudioError::InputFailed(_) => "input_failed"
+		}
+	}
+}
+
+impl fmt::Display for AudioError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AudioError::DeviceNotFound { host_name, device_name } => {
+				write!(f, "Couldn't find output device '{}' on host '{}'", device_name, host_name)
+			},
+			AudioError::ConfigUnavailable(reason) => {
+				write!(f, "Couldn't get the configurations of the output device: {}", reason)
+			},
+			AudioError::NoSupportedConfig(device_name) => {
+				write!(f, "Output device '{}' has no configuration the engine can use", device_name)
+			},
+			AudioError::StreamFailed(reason) => {
+				write!(f, "Couldn't open the output stream: {}", reason)
+			},
+			AudioError::NoInputDevice => {
+				write!(f, "No input device available")
+			},
+			AudioError::InputFailed(reason) => {
+				write!(f, "Couldn't open the input: {}", reason)
+			}
+		}
+	}
+}
+
+impl std::error::Error for AudioError {}
\ No newline at end of file
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use crate::core::node::MAX_BLOCK_SIZE;
+use crate::core::error::AudioError;
+use crate::core::wav::read_wav;
+use crate::core::stats::SharedEngineStats;
+
+extern crate ringbuf;
+
+// How many blocks of captured audio are kept buffered before the graph starts reading them.
+// Together with the output ring buffer, this is the latency from input to output.
+pub const INPUT_LATENCY_BLOCKS: usize = 1;
+
+// One block of interleaved input samples, read by every AudioInputNode in the graph
+pub type SharedInputBuffer = Arc<Mutex<Vec<f32>>>;
+
+pub fn new_shared_input_buffer(channels: usize) -> SharedInputBuffer {
+	// room for the largest block, so changing the block size never allocates on the graph thread
+	let mut buffer = Vec::with_capacity(MAX_BLOCK_SIZE * channels);
+	buffer.resize(MAX_BLOCK_SIZE * channels, 0.0);
+
+	Arc::new(Mutex::new(buffer))
+}
+
+// Loops over the samples of a WAV file, as a stand-in for an input device
+pub struct FileInput {
+	samples: Vec<f32>,
+	channels: usize,
+	position: usize
+}
+
+impl FileInput {
+	// The file isn't resampled, so it has to be at the rate the engine runs at
+	pub fn open(path: &str, sample_rate: u32) -> Result<FileInput, AudioError> {
+		let file = File::open(path).map_err(|e| AudioError::InputFailed(format!("{}: {}", path, e)))?;
+		let wav = read_wav(BufReader::new(file)).map_err(|e| AudioError::InputFailed(format!("{}: {}", path, e)))?;
+
+		if wav.sample_rate != sample_rate {
+			return Err(AudioError::InputFailed(format!("{} is at {} Hz, but the engine runs at {} Hz", path, wav.sample_rate, sample_rate)));
+		}
+
+		Ok(FileInput {
+			samples: wav.samples,
+			channels: usize::from(wav.channels),
+			position: 0
+		})
+	}
+
+	pub fn channels(&self) -> usize {
+		self.channels
+	}
+
+	pub fn read(&mut self, output: &mut [f32]){
+		if self.samples.is_empty() {
+			output.iter_mut().for_each(|sample| *sample = 0.0);
+			return;
+		}
+
+		for sample in output.iter_mut() {
+			*sample = self.samples[self.position];
+			self.position = (self.position + 1) % self.samples.len();
+		}
+	}
+}
+
+enum InputSource {
+	// filled by an input stream, at the pace of the device
+	Stream(ringbuf::Consumer<f32>),
+	// read directly, for offline rendering
+	File(FileInput)
+}
+
+// Moves one block of captured audio into the shared input buffer before every block the graph renders.
+// Lives on the graph thread.
+pub struct AudioInput {
+	source: InputSource,
+	channels: usize,
+	buffer: SharedInputBuffer,
+
+	// streams only hand out samples once INPUT_LATENCY_BLOCKS are buffered on top of the block being read,
+	// and start over after running dry, so the latency stays the same after a dropout
+	is_primed: bool,
+	// counts the samples thrown away to keep the latency, for streams
+	stats: Option<SharedEngineStats>
+}
+
+impl AudioInput {
+	pub fn from_stream(consumer: ringbuf::Consumer<f32>, channels: usize, stats: SharedEngineStats) -> AudioInput {
+		AudioInput {
+			source: InputSource::Stream(consumer),
+			channels,
+			buffer: new_shared_input_buffer(channels),
+
+			is_primed: false,
+			stats: Some(stats)
+		}
+	}
+
+	pub fn from_file(file_input: FileInput) -> AudioInput {
+		AudioInput {
+			channels: file_input.channels(),
+			buffer: new_shared_input_buffer(file_input.channels()),
+			source: InputSource::File(file_input),
+
+			is_primed: true,
+			stats: None
+		}
+	}
+
+	// The buffer the input nodes read from
+	pub fn get_buffer(&self) -> SharedInputBuffer {
+		self.buffer.clone()
+	}
+
+	pub fn channels(&self) -> usize {
+		self.channels
+	}
+
+	// Called before every block the graph renders, with the number of frames in it
+	pub fn pull_block(&mut self, num_frames: usize){
+		let mut buffer = self.buffer.lock().unwrap();
+		buffer.resize(num_frames * self.channels, 0.0);
+
+		let consumer = match &mut self.source {
+			InputSource::Stream(consumer) => consumer,
+			InputSource::File(file_input) => {
+				file_input.read(&mut buffer);
+				return;
+			}
+		};
+
+		let block_len = buffer.len();
+		let latency_len = INPUT_LATENCY_BLOCKS * block_len;
+
+		if !self.is_primed && consumer.len() >= latency_len + block_len {
+			self.is_primed = true;
+		}
+
+		if self.is_primed && consumer.len() >= block_len {
+			// the device clock runs a little faster than the output's, so drop what piled up beyond the latency
+			let excess = consumer.len().saturating_sub(latency_len + 2 * block_len);
+			let excess = excess - excess % self.channels;
+
+			if excess > 0 {
+				consumer.discard(excess);
+
+				if let Some(stats) = &self.stats {
+					stats.record_overrun();
+				}
+			}
+
+			consumer.pop_slice(&mut buffer);
+		} else {
+			self.is_primed = false;
+			buffer.iter_mut().for_each(|sample| *sample = 0.0);
+		}
+	}
+}
\ No newline at end of file
@@ -1,154 +1,232 @@
-use std::ptr;
 use std::fmt;
-use crate::core::heaped::Heaped;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::core::arena::ArenaId;
+use crate::core::error::GraphError;
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::profile::{NodeTiming, SharedNodeTiming};
+
+pub const DEFAULT_BLOCK_SIZE: usize = 256;
+// Block sizes the engine can be set to, in frames
+pub const MIN_BLOCK_SIZE: usize = 32;
+pub const MAX_BLOCK_SIZE: usize = 2048;
+pub const DEFAULT_SAMPLE_RATE: f32 = 44100.0;
+
+// What the engine is running at, handed to every behavior before it processes anything
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessContext {
+	pub sample_rate: f32,
+	pub block_size: usize
+}
 
-pub const BUFFER_SIZE: usize = 256;
+impl ProcessContext {
+	pub fn new(sample_rate: f32, block_size: usize) -> ProcessContext {
+		ProcessContext {
+			sample_rate,
+			block_size
+		}
+	}
+}
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
-pub struct NodeId(pub(crate) usize);
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct NodeId(pub(crate) ArenaId);
 
 impl fmt::Display for NodeId {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "node{}", self.0)
+		write!(f, "node{}_{}", self.0.index, self.0.generation)
 	}
 }
 
+// The arguments a node was created with from its recipe, as they are stored in a patch
+pub type NodeParameters = BTreeMap<String, serde_json::Value>;
+
+// Stable identity of an output buffer, which the compiled plan maps to a slot in its buffer pool
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
+pub(crate) struct BufferId(pub(crate) ArenaId);
+
+// An input sums the buffers of every edge connected to it, each scaled by the edge's gain
 pub struct NodeIn {
 	pub buffer: Vec<f32>,
-	from_buffer: *const Vec<f32>,
+	pub(crate) sources: Vec<(usize, f32)>,
 }
 
 impl NodeIn {
-	fn new() -> NodeIn {
+	pub(crate) fn new(sources: Vec<(usize, f32)>, block_size: usize) -> NodeIn {
 		NodeIn { 
-			buffer: vec![0.0; BUFFER_SIZE],
-			from_buffer: ptr::null()
+			buffer: vec![0.0; block_size],
+			sources
+		}
+	}
+
+	// get_buffer gives the samples of a buffer of the plan's pool
+	pub(crate) fn update<'a, F: Fn(usize) -> &'a [f32]>(&mut self, get_buffer: F){
+		match self.sources.as_slice() {
+			[] => self.buffer.fill(0.0),
+			[(buffer_idx, gain)] if *gain == 1.0 => self.buffer.copy_from_slice(get_buffer(*buffer_idx)),
+			sources => {
+				self.buffer.fill(0.0);
+
+				for (buffer_idx, gain) in sources.iter() {
+					for (sum, sample) in self.buffer.iter_mut().zip(get_buffer(*buffer_idx).iter()) {
+						*sum += sample * gain;
+					}
+				}
+			}
 		}
 	}
 }
 
 pub struct NodeOut {
-	pub buffer: Vec<f32>
+	pub buffer: Vec<f32>,
+	pub(crate) buffer_idx: usize
 }
 
 impl NodeOut {
-	fn new() -> NodeOut {
+	// The samples live in the plan's buffer pool, and are only moved into the NodeOut while the node updates
+	pub(crate) fn new(buffer_idx: usize) -> NodeOut {
 		NodeOut { 
-			buffer: vec![0.0; BUFFER_SIZE],
+			buffer: Vec::new(),
+			buffer_idx
 		}
 	}
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Deserialize, Serialize)]
+pub enum EdgeKind {
+	#[default]
+	Normal,
+	// Reads the output of the previous block, so it can close a loop in the graph.
+	// Adds a latency of one block.
+	Feedback
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub(crate) struct NodeEdge {
-	pub(crate) from: Heaped<Node>,
+	pub(crate) from: NodeId,
 	pub(crate) from_out_idx: usize,
-	pub(crate) to: Heaped<Node>,
+	pub(crate) to: NodeId,
 	pub(crate) to_in_idx: usize,
 
-	pub(crate) from_buffer: *const Vec<f32>
+	pub(crate) kind: EdgeKind,
+	pub(crate) gain: f32,
+	pub(crate) from_buffer: BufferId
 }
 
+impl NodeEdge {
+	pub(crate) fn connects(&self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize) -> bool {
+		self.from == from && self.from_out_idx == from_out_idx && self.to == to && self.to_in_idx == to_in_idx
+	}
+}
+
+#[derive(Clone, Debug)]
 pub struct NodeBehaviorInfo {
 	pub type_name: String,
 	pub num_ins: usize,
-	pub num_outs: usize
+	pub num_outs: usize,
+	pub parameters: Vec<ParameterDescriptor>
 }
 
-pub trait NodeBehavior {
+// Behaviors are created on the control thread and then handed over to the render thread
+pub trait NodeBehavior: Send {
 	fn get_info(&self) -> NodeBehaviorInfo;
-	fn update(&mut self, inputs: &Vec<NodeIn>, outputs: &mut Vec<NodeOut>);
+	// Called on the control thread when the behavior is added to a graph, before the first update,
+	// and again on the render thread whenever the sample rate or block size changes. It shouldn't allocate in that case,
+	// so anything sized by the block should make room for MAX_BLOCK_SIZE up front.
+	fn prepare(&mut self, _context: &ProcessContext){
+		//
+	}
+	// Every input and output buffer holds num_frames samples, which is the block size of the context
+	fn update(&mut self, inputs: &[NodeIn], outputs: &mut Vec<NodeOut>, num_frames: usize);
+	// Parameters are addressed by their index in get_info().parameters.
+	// Values are validated against the descriptor before they get here.
+	fn get_parameter(&self, _id: ParameterId) -> Option<ParameterValue> {
+		None
+	}
+	fn set_parameter(&mut self, _id: ParameterId, _value: ParameterValue){
+		//
+	}
 	fn before_drop(&mut self){
 		//
 	}
 }
 
+// The control thread's view of a node. Its behavior and buffers live in the renderer's plan.
 pub(crate) struct Node {
 	pub(crate) name: String,
 	pub(crate) id: NodeId,
+	pub(crate) info: NodeBehaviorInfo,
+
+	// only nodes created from a recipe can be saved to a patch
+	pub(crate) recipe: Option<String>,
+	pub(crate) parameters: NodeParameters,
+	// last value set for every parameter in info.parameters, since the behavior itself is out of reach on the render thread
+	pub(crate) parameter_values: Vec<ParameterValue>,
 
-	pub(crate) ins: Vec<NodeIn>,
-	pub(crate) outs: Vec<NodeOut>,
+	pub(crate) out_buffers: Vec<BufferId>,
 
 	pub(crate) edges_in: Vec<NodeEdge>,
 	pub(crate) edges_out: Vec<NodeEdge>,
 
-	pub(crate) behavior: Box<dyn NodeBehavior>
+	// filled by the renderer while profiling is on
+	pub(crate) timing: SharedNodeTiming
 }
 
 impl Node {
-	pub(crate) fn new(name: String, id: NodeId, behavior: Box<dyn NodeBehavior>) -> Node {
-		let info = behavior.get_info();
-
-		let mut ins = Vec::with_capacity(info.num_ins);
-
-		for _ in 0..info.num_ins {
-			ins.push(NodeIn::new());
-		}
-
-		let mut outs = Vec::with_capacity(info.num_outs);
-
-		for _ in 0..info.num_outs {
-			outs.push(NodeOut::new());
+	pub(crate) fn new(name: String, id: NodeId, info: NodeBehaviorInfo, recipe: Option<String>, parameters: NodeParameters, parameter_values: Vec<ParameterValue>, out_buffers: Vec<BufferId>) -> Node {
+		if out_buffers.len() != info.num_outs {
+			panic!("Trying to create a node with {} output buffers, but its behavior has {} outputs!", out_buffers.len(), info.num_outs);
 		}
 
 		Node { 
 			name, 
 			id,
+			info,
 
-			ins, 
-			outs,
-			
-			behavior,
+			recipe,
+			parameters,
+			parameter_values,
 
-			edges_in: Vec::new(),
-			edges_out: Vec::new()
-		}
-	}
+			out_buffers,
 
-	pub(crate) fn destroy(&mut self) -> &mut Box<dyn NodeBehavior>{
-		for edge_in in self.edges_in.iter_mut() {
-			unsafe {
-				(*edge_in.from.mut_ptr).remove_output_edge(edge_in);
-			}
-		}
+			edges_in: Vec::new(),
+			edges_out: Vec::new(),
 
-		for edge_out in self.edges_out.iter_mut() {
-			unsafe {
-				(*edge_out.to.mut_ptr).remove_input_edge(edge_out);
-			}
+			timing: NodeTiming::new()
 		}
-
-		return &mut self.behavior;
 	}
 
-	pub(crate) fn get_output_buffer(&mut self, output_idx: usize) -> *mut Vec<f32> {
-		if let Some(outp) = self.outs.get_mut(output_idx) {
-			return &mut outp.buffer
+	pub(crate) fn get_output_buffer(&self, output_idx: usize) -> Result<BufferId, GraphError> {
+		if let Some(buffer_id) = self.out_buffers.get(output_idx) {
+			Ok(*buffer_id)
 		} else {
-			panic!("Trying to get an output buffer that doesn't exist!")
+			Err(GraphError::OutputOutOfRange { node: self.id, output_idx, num_outs: self.out_buffers.len() })
 		}
 	}
 
-	pub(crate) fn add_input_edge(&mut self, edge: NodeEdge){
-		if edge.to.mut_ptr != self {
+	pub(crate) fn get_input_edges(&self, input_idx: usize) -> impl Iterator<Item = &NodeEdge> {
+		self.edges_in.iter().filter(move |edge| edge.to_in_idx == input_idx)
+	}
+
+	pub(crate) fn add_input_edge(&mut self, edge: NodeEdge) -> Result<(), GraphError> {
+		if edge.to != self.id {
 			panic!("Trying to add an input edge to the wrong node!")
 		}
 
-		if let Some(inp) = self.ins.get_mut(edge.to_in_idx) {
-			if !inp.from_buffer.is_null() {
-				panic!("Trying to add an input edge but the input is already connected to something else!");
-			}
+		if edge.to_in_idx >= self.info.num_ins {
+			return Err(GraphError::InputOutOfRange { node: self.id, input_idx: edge.to_in_idx, num_ins: self.info.num_ins });
+		}
 
-			inp.from_buffer = edge.from_buffer;
-			self.edges_in.push(edge);
-		} else {
-			panic!("Trying to add an input edge but the input does not exist!");
+		if self.edges_in.iter().any(|e| e.connects(edge.from, edge.from_out_idx, edge.to, edge.to_in_idx)) {
+			return Err(GraphError::EdgeAlreadyExists { from: edge.from, from_out_idx: edge.from_out_idx, to: edge.to, to_in_idx: edge.to_in_idx });
 		}
+
+		self.edges_in.push(edge);
+
+		Ok(())
 	}
 
 	pub(crate) fn add_output_edge(&mut self, edge: NodeEdge){
-		if edge.from.mut_ptr != self {
+		if edge.from != self.id {
 			panic!("Trying to add an output edge to the wrong node!")
 		}
 
@@ -158,10 +236,6 @@ impl Node {
 	pub(crate) fn remove_input_edge(&mut self, edge: &NodeEdge){
 		if let Some(pos) = self.edges_in.iter().position(|e| e == edge) {
 			self.edges_in.swap_remove(pos);
-
-			let inp = self.ins.get_mut(edge.to_in_idx).unwrap();
-			inp.from_buffer = ptr::null();
-
 		} else {
 			panic!("Trying to remove an input edge that doesn't exist!")
 		}
@@ -174,23 +248,4 @@ impl Node {
 			panic!("Trying to remove an output edge that doesn't exist!")
 		}
 	}
-
-	pub(crate) fn update_inputs(&mut self){
-		for inp in self.ins.iter_mut(){
-			if inp.from_buffer.is_null() {
-				inp.buffer.fill(0.0);
-
-			} else {
-				unsafe {
-					inp.buffer.copy_from_slice(&(*inp.from_buffer)[0..]);
-				}
-			}
-		}
-	}
-
-	pub(crate) fn update(&mut self){
-		self.update_inputs();
-
-		self.behavior.update(&self.ins, &mut self.outs);
-	}
 }
\ No newline at end of file
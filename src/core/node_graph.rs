@@ -1,227 +1,840 @@
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
-use crate::core::heaped::Heaped;
-use crate::core::node::{Node, NodeBehavior, NodeEdge, NodeId};
-
+use std::sync::Mutex;
+use crate::core::arena::Arena;
+use crate::core::node::{BufferId, EdgeKind, Node, NodeBehavior, NodeEdge, NodeId, NodeParameters, ProcessContext, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::patch::{Patch, PatchEdge, PatchNode, PATCH_FORMAT_VERSION};
+use crate::core::plan::{Plan, PlanLayout};
+use crate::core::profile::NodeProfile;
+use crate::core::renderer::{RenderCommand, Renderer, COMMAND_QUEUE_SIZE};
+use crate::core::error::GraphError;
+
+extern crate ringbuf;
+
+// The editable graph, owned by the control thread. Edits only take effect in the renderer
+// once they are compiled into a new plan by commit().
 pub struct NodeGraph {
-	nodes: Vec<Heaped<Node>>,
-	map: HashMap<NodeId, Heaped<Node>>,
-	sorted: Vec<Heaped<Node>>,
+	context: ProcessContext,
+
+	nodes: Arena<Node>,
+	buffer_ids: Arena<()>,
+
+	// behaviors of nodes added since the last commit
+	new_behaviors: HashMap<NodeId, Box<dyn NodeBehavior>>,
+	// layout of the last plan handed to the renderer
+	layout: PlanLayout,
+
+	commands: ringbuf::Producer<RenderCommand>,
+	garbage: ringbuf::Consumer<Box<Plan>>,
 
-	prev_node_id_val: usize,
-	is_dirty: bool
+	is_dirty: bool,
+	is_profiling: bool,
+
+	// kept from the patch the graph was loaded from, so saving it again doesn't lose it
+	metadata: BTreeMap<String, String>
 }
 
 impl NodeGraph {
-	pub fn new() -> NodeGraph {
-		NodeGraph {
-			nodes: Vec::new(),
-			map: HashMap::new(),
-			sorted: Vec::new(),
+	// Creates an empty graph, along with the renderer that will run it
+	pub fn new(context: ProcessContext) -> (NodeGraph, Renderer) {
+		let (commands_prod, commands_cons) = ringbuf::RingBuffer::<RenderCommand>::new(COMMAND_QUEUE_SIZE).split();
+		let (garbage_prod, garbage_cons) = ringbuf::RingBuffer::<Box<Plan>>::new(COMMAND_QUEUE_SIZE).split();
 
-			prev_node_id_val: 0,
-			is_dirty: false
-		}
+		let graph = NodeGraph {
+			context,
+
+			nodes: Arena::new(),
+			buffer_ids: Arena::new(),
+
+			new_behaviors: HashMap::new(),
+			layout: PlanLayout::default(),
+
+			commands: commands_prod,
+			garbage: garbage_cons,
+
+			is_dirty: false,
+			is_profiling: false,
+
+			metadata: BTreeMap::new()
+		};
+
+		(graph, Renderer::new(context, commands_cons, garbage_prod))
 	}
 
+	// Adds a node with a behavior that was built by hand. Graphs with such nodes can't be saved as a patch.
+	#[allow(dead_code)]
 	pub fn add_node(&mut self, name: &str, behavior: Box<dyn NodeBehavior>) -> NodeId {
+		self.insert_node(name, behavior, None, NodeParameters::new())
+	}
+
+	pub fn add_node_by_recipe(&mut self, name: &str, recipe_name: &str, mut parameters: NodeParameters) -> Result<NodeId, GraphError> {
+		let behavior = get_node_by_recipe(recipe_name, &mut parameters)?;
+
+		Ok(self.insert_node(name, behavior, Some(recipe_name.to_string()), parameters))
+	}
+
+	fn insert_node(&mut self, name: &str, mut behavior: Box<dyn NodeBehavior>, recipe: Option<String>, parameters: NodeParameters) -> NodeId {
 		println!("Adding node '{}'", name);
 
-		self.prev_node_id_val += 1;
-		let id = NodeId(self.prev_node_id_val);
+		behavior.prepare(&self.context);
 
-		let node = Heaped::new_with_value(Node::new(name.to_string(), id, behavior));
+		let info = behavior.get_info();
+		let out_buffers: Vec<BufferId> = (0..info.num_outs).map(|_| BufferId(self.buffer_ids.insert(()))).collect();
+		let parameter_values: Vec<ParameterValue> = info.parameters.iter().enumerate().map(|(parameter_id, descriptor)| {
+			behavior.get_parameter(parameter_id).unwrap_or(descriptor.default)
+		}).collect();
 
-		self.nodes.push(node);
-		self.map.insert(id, node);
+		let id = NodeId(self.nodes.insert_with(|arena_id| Node::new(name.to_string(), NodeId(arena_id), info, recipe, parameters, parameter_values, out_buffers)));
 
+		self.new_behaviors.insert(id, behavior);
 		self.is_dirty = true;
 
-		return id;
+		id
 	}
 
-    /*
-	pub fn add_node_by_recipe(&mut self, name: &str, recipe_name: &str) -> NodeId {
-		let behavior = get_node_by_recipe(recipe_name);
-
-		self.add_node(name, behavior)
-	}
-    */
+	pub fn remove_node(&mut self, node_id: NodeId) -> Result<(), GraphError> {
+		let (edges_in, edges_out) = match self.nodes.get(node_id.0) {
+			Some(node) => (node.edges_in.clone(), node.edges_out.clone()),
+			None => return Err(GraphError::NodeNotFound(node_id))
+		};
 
-	pub fn remove_node(&mut self, node_id: NodeId){
-		if let Some(heaped_node) = self.map.remove(&node_id){
-			unsafe {
-				let mut behavior = (*heaped_node.mut_ptr).destroy();
-				behavior.before_drop();
+		// edges looping back into the node itself disappear along with it
+		for edge_in in edges_in.iter().filter(|edge| edge.from != node_id) {
+			if let Some(from_node) = self.nodes.get_mut(edge_in.from.0) {
+				from_node.remove_output_edge(edge_in);
 			}
+		}
 
-			if let Some(pos) = self.nodes.iter().position(|heaped| *heaped == heaped_node) {
-				self.nodes.swap_remove(pos);
+		for edge_out in edges_out.iter().filter(|edge| edge.to != node_id) {
+			if let Some(to_node) = self.nodes.get_mut(edge_out.to.0) {
+				to_node.remove_input_edge(edge_out);
 			}
+		}
 
-		} else {
-			panic!("Tried to remove a node that doesn't exist!");
+		let node = self.nodes.remove(node_id.0).unwrap();
+
+		for buffer_id in node.out_buffers.iter() {
+			self.buffer_ids.remove(buffer_id.0);
+		}
+
+		// a node that never made it to the renderer still owns its behavior,
+		// otherwise the behavior is dropped along with the plan it was last part of
+		if let Some(mut behavior) = self.new_behaviors.remove(&node_id) {
+			behavior.before_drop();
 		}
 
 		self.is_dirty = true;
+
+		Ok(())
+	}
+
+	pub fn connect(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize) -> Result<(), GraphError> {
+		self.connect_with_kind(from, from_out_idx, to, to_in_idx, EdgeKind::Normal)
 	}
 
-	pub fn connect(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize) {
-		let from_heaped: Heaped<Node>;
-		let to_heaped: Heaped<Node>;
+	// Connects through a one block delay, which is allowed to close a loop
+	pub fn connect_feedback(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize) -> Result<(), GraphError> {
+		self.connect_with_kind(from, from_out_idx, to, to_in_idx, EdgeKind::Feedback)
+	}
 
-		if let Some(heaped_from) = self.map.get(&from) {
-			from_heaped = heaped_from.clone();
-		} else {
-			panic!("Trying to connect from a non-existing node!");
-		}
+	fn connect_with_kind(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize, kind: EdgeKind) -> Result<(), GraphError> {
+		let from_buffer = match self.nodes.get(from.0) {
+			Some(from_node) => from_node.get_output_buffer(from_out_idx)?,
+			None => return Err(GraphError::NodeNotFound(from))
+		};
 
-		if let Some(heaped_to) = self.map.get(&to) {
-			to_heaped = heaped_to.clone();
-		} else {
-			panic!("Trying to connect to a non-existing node!");
+		let edge = NodeEdge {
+			from,
+			from_out_idx,
+
+			to,
+			to_in_idx,
+
+			kind,
+			gain: 1.0,
+			from_buffer
+		};
+
+		// the input edge is added first, since it is the one that can be refused
+		match self.nodes.get_mut(to.0) {
+			Some(to_node) => to_node.add_input_edge(edge)?,
+			None => return Err(GraphError::NodeNotFound(to))
 		}
 
-		let from_buffer: *mut Vec<f32>;
+		if kind == EdgeKind::Normal {
+			let cycle = self.find_loop_node_ids(to, from);
+
+			if !cycle.is_empty() {
+				self.nodes.get_mut(to.0).unwrap().remove_input_edge(&edge);
 
-		unsafe {
-			from_buffer = (*from_heaped.mut_ptr).get_output_buffer(from_out_idx);
+				return Err(GraphError::CycleDetected(cycle));
+			}
 		}
 
-		let edge = NodeEdge {
-			from: from_heaped,
-			from_out_idx: from_out_idx,
+		self.nodes.get_mut(from.0).unwrap().add_output_edge(edge);
+
+		self.is_dirty = true;
+
+		Ok(())
+	}
 
-			to: to_heaped,
-			to_in_idx: to_in_idx,
+	pub fn disconnect(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize) -> Result<(), GraphError> {
+		if !self.nodes.contains(from.0) {
+			return Err(GraphError::NodeNotFound(from));
+		}
 
-			from_buffer: from_buffer
+		let maybe_edge: Option<NodeEdge> = match self.nodes.get(to.0) {
+			Some(to_node) => to_node.edges_in.iter().find(|edge| edge.connects(from, from_out_idx, to, to_in_idx)).copied(),
+			None => return Err(GraphError::NodeNotFound(to))
 		};
 
-		unsafe {
-			(*from_heaped.mut_ptr).add_output_edge(edge);
-			(*to_heaped.mut_ptr).add_input_edge(edge);
+		if let Some(edge) = maybe_edge {
+			self.nodes.get_mut(from.0).unwrap().remove_output_edge(&edge);
+			self.nodes.get_mut(to.0).unwrap().remove_input_edge(&edge);
+		} else {
+			return Err(GraphError::EdgeNotFound { from, from_out_idx, to, to_in_idx });
 		}
 
 		self.is_dirty = true;
+
+		Ok(())
 	}
 
-	pub fn disconnect(&mut self, from: NodeId, from_idx: usize, to: NodeId, to_idx: usize) {
-		// TODO
+	// Scales what an edge adds to the sum at its input
+	pub fn set_edge_gain(&mut self, from: NodeId, from_out_idx: usize, to: NodeId, to_in_idx: usize, gain: f32) -> Result<(), GraphError> {
+		if !self.nodes.contains(from.0) {
+			return Err(GraphError::NodeNotFound(from));
+		}
+
+		let to_node = match self.nodes.get_mut(to.0) {
+			Some(to_node) => to_node,
+			None => return Err(GraphError::NodeNotFound(to))
+		};
+
+		match to_node.edges_in.iter_mut().find(|edge| edge.connects(from, from_out_idx, to, to_in_idx)) {
+			Some(edge_in) => edge_in.gain = gain,
+			None => return Err(GraphError::EdgeNotFound { from, from_out_idx, to, to_in_idx })
+		}
+
+		let from_node = self.nodes.get_mut(from.0).unwrap();
+
+		if let Some(edge_out) = from_node.edges_out.iter_mut().find(|edge| edge.connects(from, from_out_idx, to, to_in_idx)) {
+			edge_out.gain = gain;
+		}
+
+		self.is_dirty = true;
+
+		Ok(())
 	}
 
-	fn sort(&mut self){
+	// Kahn's algorithm over the normal edges. Feedback edges read the previous block, so they don't constrain the order.
+	// Nodes on a cycle of normal edges, or downstream from one, are left out.
+	fn sorted_node_ids(&self) -> Vec<NodeId> {
 		let mut num_in_edges_map: HashMap<NodeId, usize> = HashMap::new();
-		let mut queue: VecDeque<Heaped<Node>> = VecDeque::new();
+		let mut queue: VecDeque<NodeId> = VecDeque::new();
 
-		for heaped_node in self.nodes.iter_mut() {
-			let node: &Node = unsafe { & *heaped_node.const_ptr };
-			let num_in_edges = node.edges_in.len();
+		for (_, node) in self.nodes.iter() {
+			let num_in_edges = node.edges_in.iter().filter(|edge| edge.kind == EdgeKind::Normal).count();
 
 			num_in_edges_map.insert(node.id, num_in_edges);
 
 			if num_in_edges == 0 {
-				queue.push_back(heaped_node.clone());
+				queue.push_back(node.id);
 			}
 		}
 
-		self.sorted.clear();
-		while !queue.is_empty() {
-			let front = queue.pop_front().unwrap();
-			self.sorted.push(front.clone());
-
-			let node: &Node = unsafe { & *front.const_ptr };
-			for edge_out in node.edges_out.iter() {
-				let to_node: &Node = unsafe { & *edge_out.to.const_ptr };
+		let mut sorted: Vec<NodeId> = Vec::with_capacity(num_in_edges_map.len());
+		while let Some(front) = queue.pop_front() {
+			sorted.push(front);
 
-				let num_in_edges_left: &mut usize = num_in_edges_map.get_mut(&to_node.id).unwrap();
+			let node = self.nodes.get(front.0).unwrap();
+			for edge_out in node.edges_out.iter().filter(|edge| edge.kind == EdgeKind::Normal) {
+				let num_in_edges_left: &mut usize = num_in_edges_map.get_mut(&edge_out.to).unwrap();
 				*num_in_edges_left -= 1;
 
 				if *num_in_edges_left == 0 {
-					queue.push_back(edge_out.to.clone());
+					queue.push_back(edge_out.to);
+				}
+			}
+		}
+
+		sorted
+	}
+
+	// Returns the descriptor and current value of every parameter of the node
+	pub fn get_parameters(&self, node_id: NodeId) -> Result<Vec<(ParameterDescriptor, ParameterValue)>, GraphError> {
+		match self.nodes.get(node_id.0) {
+			Some(node) => Ok(node.info.parameters.iter().cloned().zip(node.parameter_values.iter().copied()).collect()),
+			None => Err(GraphError::NodeNotFound(node_id))
+		}
+	}
+
+	// Returns the arguments the node was created with, which can't change while it runs
+	pub fn get_creation_parameters(&self, node_id: NodeId) -> Result<NodeParameters, GraphError> {
+		match self.nodes.get(node_id.0) {
+			Some(node) => Ok(node.parameters.clone()),
+			None => Err(GraphError::NodeNotFound(node_id))
+		}
+	}
+
+	// Changes a parameter of a running node. Unlike edits to the graph, this doesn't wait for a commit.
+	pub fn set_parameter(&mut self, node_id: NodeId, parameter_id: ParameterId, value: ParameterValue) -> Result<(), GraphError> {
+		let node = match self.nodes.get_mut(node_id.0) {
+			Some(node) => node,
+			None => return Err(GraphError::NodeNotFound(node_id))
+		};
+
+		let value = match node.info.parameters.get(parameter_id) {
+			Some(descriptor) => descriptor.validate(value)?,
+			None => return Err(GraphError::ParameterNotFound { node: node_id, parameter_id, num_parameters: node.info.parameters.len() })
+		};
+
+		// a node that hasn't been committed yet still has its behavior on this side
+		if let Some(behavior) = self.new_behaviors.get_mut(&node_id) {
+			behavior.set_parameter(parameter_id, value);
+		} else {
+			let node_idx = self.layout.get_node_slot(node_id).expect("Committed nodes should be part of the plan layout!");
+
+			if self.commands.push(RenderCommand::SetParameter { node_idx, id: parameter_id, value }).is_err() {
+				return Err(GraphError::RendererBusy);
+			}
+		}
+
+		node.parameter_values[parameter_id] = value;
+
+		Ok(())
+	}
+
+	pub fn get_context(&self) -> ProcessContext {
+		self.context
+	}
+
+	// Changes the sample rate of every node, which happens between two blocks on the render thread
+	pub fn set_sample_rate(&mut self, sample_rate: f32) -> Result<(), GraphError> {
+		let context = ProcessContext { sample_rate, ..self.context };
+
+		if self.commands.push(RenderCommand::Prepare(context)).is_err() {
+			return Err(GraphError::RendererBusy);
+		}
+
+		for behavior in self.new_behaviors.values_mut() {
+			behavior.prepare(&context);
+		}
+
+		self.context = context;
+
+		Ok(())
+	}
+
+	// Changes the number of frames in a block. Every buffer has to be reallocated for that, so the whole graph
+	// is compiled into a new plan right away, without any samples carried over, and the nodes are prepared again.
+	pub fn set_block_size(&mut self, block_size: usize) -> Result<(), GraphError> {
+		if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+			return Err(GraphError::InvalidBlockSize(block_size));
+		}
+
+		self.collect_garbage();
+
+		if self.commands.is_full() {
+			return Err(GraphError::RendererBusy);
+		}
+
+		let context = ProcessContext { block_size, ..self.context };
+
+		for behavior in self.new_behaviors.values_mut() {
+			behavior.prepare(&context);
+		}
+
+		self.context = context;
+		self.send_plan(Some(context));
+
+		Ok(())
+	}
+
+	// Turns the timing of every node update on or off. Turning it on starts the timings over.
+	pub fn set_profiling(&mut self, is_profiling: bool) -> Result<(), GraphError> {
+		if self.commands.push(RenderCommand::SetProfiling(is_profiling)).is_err() {
+			return Err(GraphError::RendererBusy);
+		}
+
+		if is_profiling {
+			for (_, node) in self.nodes.iter() {
+				node.timing.reset();
+			}
+		}
+
+		self.is_profiling = is_profiling;
+
+		Ok(())
+	}
+
+	pub fn is_profiling(&self) -> bool {
+		self.is_profiling
+	}
+
+	// How long a block lasts, which is the time the renderer has to update every node
+	pub fn get_block_budget_us(&self) -> f32 {
+		self.context.block_size as f32 * 1_000_000.0 / self.context.sample_rate
+	}
+
+	// Returns the timings of every node since profiling was turned on, in the order they are updated.
+	// Nodes the renderer doesn't update, like those on a cycle, come last.
+	pub fn get_profile(&self) -> Vec<(NodeId, NodeProfile)> {
+		let block_us = self.get_block_budget_us();
+
+		let mut node_ids = self.sorted_node_ids();
+		let sorted: HashSet<NodeId> = node_ids.iter().copied().collect();
+		node_ids.extend(self.nodes.iter().map(|(_, node)| node.id).filter(|node_id| !sorted.contains(node_id)));
+
+		node_ids.into_iter().map(|node_id| (node_id, self.nodes.get(node_id.0).unwrap().timing.get_profile(block_us))).collect()
+	}
+
+	// The nodes on the paths of normal edges going from start to end, which an edge from end back to start would
+	// close into a loop, or an empty list if there is no such path. Normal edges never make a loop, so there's no
+	// need to look at the whole graph: only at what's downstream of start and upstream of end.
+	fn find_loop_node_ids(&self, start: NodeId, end: NodeId) -> Vec<NodeId> {
+		let downstream = self.find_reachable_node_ids(start, true);
+
+		if !downstream.contains(&end) {
+			return Vec::new();
+		}
+
+		let upstream = self.find_reachable_node_ids(end, false);
+
+		let mut loop_node_ids: Vec<NodeId> = downstream.intersection(&upstream).copied().collect();
+		loop_node_ids.sort_by_key(|node_id| node_id.0.index);
+
+		loop_node_ids
+	}
+
+	// The node and everything it leads to through normal edges, or everything leading to it
+	fn find_reachable_node_ids(&self, node_id: NodeId, is_downstream: bool) -> HashSet<NodeId> {
+		let mut reached: HashSet<NodeId> = HashSet::from([node_id]);
+		let mut stack = vec![node_id];
+
+		while let Some(node_id) = stack.pop() {
+			let node = self.nodes.get(node_id.0).unwrap();
+			let edges = if is_downstream { &node.edges_out } else { &node.edges_in };
+
+			for edge in edges.iter().filter(|edge| edge.kind == EdgeKind::Normal) {
+				let next = if is_downstream { edge.to } else { edge.from };
+
+				if reached.insert(next) {
+					stack.push(next);
 				}
 			}
 		}
 
+		reached
+	}
+
+	// Drops the plans the renderer is done with
+	fn collect_garbage(&mut self){
+		while let Some(mut plan) = self.garbage.pop() {
+			plan.before_drop();
+		}
+	}
+
+	// Compiles the graph into a new plan and hands it over to the renderer, if anything changed since the last commit
+	pub fn commit(&mut self) -> Result<(), GraphError> {
+		self.collect_garbage();
+
+		if !self.is_dirty {
+			return Ok(());
+		}
+
+		if self.commands.is_full() {
+			return Err(GraphError::RendererBusy);
+		}
+
+		self.send_plan(None);
+
+		Ok(())
+	}
+
+	// Compiles the graph and pushes the plan to the renderer, along with the context it has to be prepared for
+	// if that changed. There has to be room in the command queue.
+	fn send_plan(&mut self, context: Option<ProcessContext>){
+		let sorted = self.sorted_node_ids();
+		let (plan, layout) = Plan::compile(&self.nodes, &sorted, &self.layout, &mut self.new_behaviors, self.context.block_size);
+
+		if self.commands.push(RenderCommand::SwapPlan { plan: Box::new(plan), context }).is_err() {
+			panic!("Render command queue filled up while committing!");
+		}
+
+		self.layout = layout;
 		self.is_dirty = false;
 	}
 
-	pub fn update(&mut self) {
-		if self.is_dirty {
-			self.sort();
+	// Describes the graph as a patch. Every node has to have been created from a recipe.
+	pub fn to_patch(&self) -> Result<Patch, GraphError> {
+		let mut patch_ids: HashMap<NodeId, usize> = HashMap::new();
+		let mut patch = Patch {
+			version: PATCH_FORMAT_VERSION,
+			metadata: self.metadata.clone(),
+			nodes: Vec::new(),
+			edges: Vec::new()
+		};
+
+		for (_, node) in self.nodes.iter() {
+			let recipe = match &node.recipe {
+				Some(recipe) => recipe.clone(),
+				None => return Err(GraphError::NotSavable(node.id))
+			};
+
+			patch_ids.insert(node.id, patch.nodes.len());
+
+			patch.nodes.push(PatchNode {
+				id: patch.nodes.len(),
+				recipe,
+				name: node.name.clone(),
+				parameters: node.parameters.clone(),
+				values: node.info.parameters.iter().map(|descriptor| descriptor.name.clone()).zip(node.parameter_values.iter().copied()).collect()
+			});
 		}
 
-		for heaped_node in self.sorted.iter_mut() {
-			let node: &mut Node = unsafe {&mut *heaped_node.mut_ptr };
-			node.update();
+		for (_, node) in self.nodes.iter() {
+			for edge in node.edges_out.iter() {
+				patch.edges.push(PatchEdge {
+					from: patch_ids[&edge.from],
+					from_out_idx: edge.from_out_idx,
+					to: patch_ids[&edge.to],
+					to_in_idx: edge.to_in_idx,
+
+					kind: edge.kind,
+					gain: edge.gain
+				});
+			}
 		}
+
+		Ok(patch)
 	}
 
-	pub fn to_dot(&mut self) -> String {
-		if self.is_dirty {
-			self.sort();
+	// Builds a graph from a patch, creating every node from its recipe
+	pub fn from_patch(patch: &Patch, context: ProcessContext) -> Result<(NodeGraph, Renderer), GraphError> {
+		if patch.version != PATCH_FORMAT_VERSION {
+			return Err(GraphError::InvalidPatch(format!("expected format version {}, got {}", PATCH_FORMAT_VERSION, patch.version)));
+		}
+
+		let (mut graph, renderer) = NodeGraph::new(context);
+		let mut node_ids: HashMap<usize, NodeId> = HashMap::new();
+
+		graph.metadata = patch.metadata.clone();
+
+		for patch_node in patch.nodes.iter() {
+			let node_id = graph.add_node_by_recipe(&patch_node.name, &patch_node.recipe, patch_node.parameters.clone())?;
+
+			if node_ids.insert(patch_node.id, node_id).is_some() {
+				return Err(GraphError::InvalidPatch(format!("node id {} is used more than once", patch_node.id)));
+			}
+
+			for (parameter_name, value) in patch_node.values.iter() {
+				let parameter_id = match graph.nodes.get(node_id.0).unwrap().info.parameters.iter().position(|descriptor| &descriptor.name == parameter_name) {
+					Some(parameter_id) => parameter_id,
+					None => return Err(GraphError::InvalidPatch(format!("node {} has no parameter named '{}'", patch_node.id, parameter_name)))
+				};
+
+				graph.set_parameter(node_id, parameter_id, *value)?;
+			}
+		}
+
+		for patch_edge in patch.edges.iter() {
+			let from = match node_ids.get(&patch_edge.from) {
+				Some(node_id) => *node_id,
+				None => return Err(GraphError::InvalidPatch(format!("edge from unknown node id {}", patch_edge.from)))
+			};
+
+			let to = match node_ids.get(&patch_edge.to) {
+				Some(node_id) => *node_id,
+				None => return Err(GraphError::InvalidPatch(format!("edge to unknown node id {}", patch_edge.to)))
+			};
+
+			graph.connect_with_kind(from, patch_edge.from_out_idx, to, patch_edge.to_in_idx, patch_edge.kind)?;
+
+			if patch_edge.gain != 1.0 {
+				graph.set_edge_gain(from, patch_edge.from_out_idx, to, patch_edge.to_in_idx, patch_edge.gain)?;
+			}
 		}
 
+		Ok((graph, renderer))
+	}
+
+	pub fn to_dot(&self) -> String {
+		let sorted = self.sorted_node_ids();
+
 		let mut result = String::from("digraph {\n");
 
 		result += "\tnode [shape=box]\n\n";
 
-		for (i, heaped_node) in self.sorted.iter().enumerate() {
-			let node: &Node = unsafe {& *heaped_node.const_ptr };
+		let block_us = self.get_block_budget_us();
+
+		for (i, node_id) in sorted.iter().enumerate() {
+			let node = self.nodes.get(node_id.0).unwrap();
 
-			result = std::format!("{}\t{} [label=\"{}) {}\"]\n", result, node.id, i, node.name);
+			// min / avg / max time of an update, and the share of the block budget it takes on average
+			if self.is_profiling {
+				let profile = node.timing.get_profile(block_us);
+
+				result = std::format!("{}\t{} [label=\"{}) {}\\n{}\\n{:.1} / {:.1} / {:.1} us ({:.2}%)\"]\n", result, node.id, i, node.name, node.info.type_name,
+					profile.min_us, profile.avg_us, profile.max_us, profile.budget_share * 100.0);
+			} else {
+				result = std::format!("{}\t{} [label=\"{}) {}\\n{}\"]\n", result, node.id, i, node.name, node.info.type_name);
+			}
 		}
 
 		result += "\n";
 
-		for heaped_node in self.sorted.iter() {
-			let node: &Node = unsafe {& *heaped_node.const_ptr };
+		for node_id in sorted.iter() {
+			let node = self.nodes.get(node_id.0).unwrap();
 
 			for edge_out in node.edges_out.iter() {
-				let to: &Node = unsafe {& *edge_out.to.const_ptr };
+				let mut attributes: Vec<String> = Vec::new();
+
+				if edge_out.kind == EdgeKind::Feedback {
+					attributes.push(String::from("style=dashed"));
+				}
 
-				result = std::format!("{}\t{} -> {}\n", result, node.id, to.id);
+				if edge_out.gain != 1.0 {
+					attributes.push(std::format!("label=\"x{}\"", edge_out.gain));
+				}
+
+				if attributes.is_empty() {
+					result = std::format!("{}\t{} -> {}\n", result, node.id, edge_out.to);
+				} else {
+					result = std::format!("{}\t{} -> {} [{}]\n", result, node.id, edge_out.to, attributes.join(", "));
+				}
 			}
 		}
 
 		result += "}";
 
-		return result;
+		result
 	}
 }
 
-type NodeRecipeFn = dyn FnMut() -> Box<dyn NodeBehavior>; 
+// Recipes fill in the defaults of the arguments they weren't given,
+// so the parameters of a node always say everything it was created with
+type NodeRecipeFn = dyn FnMut(&mut NodeParameters) -> Result<Box<dyn NodeBehavior>, GraphError> + Send;
 
-static mut NODE_COOKBOOK: Option<HashMap<String, Box<NodeRecipeFn>>> = None;
+static NODE_COOKBOOK: Mutex<Option<HashMap<String, Box<NodeRecipeFn>>>> = Mutex::new(None);
 
 pub fn register_node_recipe(name: &str, recipe: Box<NodeRecipeFn>){
-	unsafe {
-		if NODE_COOKBOOK.is_none() {
-			NODE_COOKBOOK = Some(HashMap::new());
-		}
+	let mut maybe_cookbook = NODE_COOKBOOK.lock().unwrap();
+	let cookbook = maybe_cookbook.get_or_insert_with(HashMap::new);
 
-		if let Some(cookbook) = &mut NODE_COOKBOOK {
-			if cookbook.contains_key(&name.to_string()) {
-				panic!("Trying to register a node recipe that already exists! Recipe was {}", name);
-			} else {
-				cookbook.insert(name.to_string(), recipe);
-			}
-		}
+	if cookbook.contains_key(name) {
+		panic!("Trying to register a node recipe that already exists! Recipe was {}", name);
 	}
+
+	cookbook.insert(name.to_string(), recipe);
 }
 
-pub fn get_node_by_recipe(name: &str) -> Box<dyn NodeBehavior> {
-	unsafe {
-		if let Some(cookbook) = &mut NODE_COOKBOOK {
-			let mut maybe_recipe = cookbook.get_mut(&name.to_string());
+pub fn get_node_by_recipe(name: &str, parameters: &mut NodeParameters) -> Result<Box<dyn NodeBehavior>, GraphError> {
+	let mut maybe_cookbook = NODE_COOKBOOK.lock().unwrap();
 
-			if let Some(recipe_fn) = &mut maybe_recipe {
-				return recipe_fn()
-			} else {
-				panic!("Couldn't find a node recipe with the following name: {}", name);
+	if let Some(recipe_fn) = maybe_cookbook.as_mut().and_then(|cookbook| cookbook.get_mut(name)) {
+		recipe_fn(parameters)
+	} else {
+		Err(GraphError::RecipeNotFound(name.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::behavior::register_node_recipes_once;
+	use crate::behavior::basic::{OutputNode, SumNode};
+	use crate::behavior::waveform::WaveformNode;
+	use crate::core::renderer::new_shared_output_buffer;
+
+	fn new_graph() -> (NodeGraph, Renderer) {
+		NodeGraph::new(ProcessContext::new(44100.0, 64))
+	}
+
+	#[test]
+	fn removed_nodes_stay_gone_when_their_slot_is_reused() {
+		let (mut graph, _renderer) = new_graph();
+		let old = graph.add_node("old", Box::new(SumNode::new(2)));
+
+		graph.remove_node(old).unwrap();
+		let new = graph.add_node("new", Box::new(SumNode::new(2)));
+
+		assert_eq!(new.0.index, old.0.index);
+		assert_eq!(graph.remove_node(old), Err(GraphError::NodeNotFound(old)));
+		assert_eq!(graph.get_parameters(old).err(), Some(GraphError::NodeNotFound(old)));
+		assert!(graph.get_parameters(new).is_ok());
+	}
+
+	#[test]
+	fn removed_nodes_free_their_buffers() {
+		let (mut graph, _renderer) = new_graph();
+		let kept = graph.add_node("kept", Box::new(WaveformNode::new(vec![1.0])));
+		let removed = graph.add_node("removed", Box::new(WaveformNode::new(vec![1.0])));
+		let removed_buffers = graph.nodes.get(removed.0).unwrap().out_buffers.clone();
+
+		graph.remove_node(removed).unwrap();
+
+		assert_eq!(graph.buffer_ids.iter().count(), 1);
+		assert!(removed_buffers.iter().all(|buffer_id| !graph.buffer_ids.contains(buffer_id.0)));
+
+		// and the next node takes the freed buffer, instead of a new one
+		let added = graph.add_node("added", Box::new(WaveformNode::new(vec![1.0])));
+		assert_eq!(graph.nodes.get(added.0).unwrap().out_buffers[0].0.index, removed_buffers[0].0.index);
+		assert!(graph.nodes.contains(kept.0));
+	}
+
+	#[test]
+	fn loops_need_a_feedback_edge() {
+		let (mut graph, _renderer) = new_graph();
+		let [source, a, b, c, sink] = ["source", "a", "b", "c", "sink"].map(|name| graph.add_node(name, Box::new(SumNode::new(1))));
+
+		graph.connect(source, 0, a, 0).unwrap();
+		graph.connect(a, 0, b, 0).unwrap();
+		graph.connect(b, 0, c, 0).unwrap();
+		graph.connect(c, 0, sink, 0).unwrap();
+
+		let before = graph.to_dot();
+
+		// only the nodes on the loop are named, not the ones leading into it or out of it
+		assert_eq!(graph.connect(c, 0, a, 0), Err(GraphError::CycleDetected(vec![a, b, c])));
+		assert_eq!(graph.connect(b, 0, b, 0), Err(GraphError::CycleDetected(vec![b])));
+		assert_eq!(graph.to_dot(), before);
+		assert_eq!(graph.sorted_node_ids(), vec![source, a, b, c, sink]);
+
+		graph.connect_feedback(c, 0, a, 0).unwrap();
+
+		assert_eq!(graph.nodes.get(a.0).unwrap().edges_in.len(), 2);
+		assert_eq!(graph.nodes.get(c.0).unwrap().edges_out.len(), 2);
+		assert_eq!(graph.sorted_node_ids(), vec![source, a, b, c, sink]);
+	}
+
+	// Edges in the order to_patch writes them in doesn't matter
+	fn sorted_edges(patch: &Patch) -> Vec<String> {
+		let mut edges: Vec<String> = patch.edges.iter().map(|edge| serde_json::to_string(edge).unwrap()).collect();
+		edges.sort();
+		edges
+	}
+
+	#[test]
+	fn patches_come_back_the_same() {
+		register_node_recipes_once();
+
+		let patch = Patch::from_json(r#"{
+			"version": 1,
+			"metadata": { "title": "round trip", "author": "iannis" },
+			"nodes": [
+				{ "id": 0, "recipe": "WaveformNode", "name": "freq", "parameters": { "waveform": [110.0] } },
+				{ "id": 1, "recipe": "PulseNode", "name": "pulse", "values": { "width": 0.25 } },
+				{ "id": 2, "recipe": "SinNode", "name": "sin" },
+				{ "id": 3, "recipe": "SumNode", "name": "mix", "parameters": { "num_ins": 2 } }
+			],
+			"edges": [
+				{ "from": 0, "from_out_idx": 0, "to": 1, "to_in_idx": 0 },
+				{ "from": 0, "from_out_idx": 0, "to": 2, "to_in_idx": 0, "gain": 2.0 },
+				{ "from": 1, "from_out_idx": 0, "to": 3, "to_in_idx": 0, "gain": 0.5 },
+				{ "from": 2, "from_out_idx": 0, "to": 3, "to_in_idx": 1 },
+				{ "from": 3, "from_out_idx": 0, "to": 2, "to_in_idx": 1, "kind": "Feedback", "gain": 0.125 }
+			]
+		}"#).unwrap();
+
+		let (graph, _renderer) = NodeGraph::from_patch(&patch, ProcessContext::new(44100.0, 64)).unwrap();
+		let saved = graph.to_patch().unwrap();
+
+		assert_eq!(saved.metadata, patch.metadata);
+		assert_eq!(sorted_edges(&saved), sorted_edges(&patch));
+
+		for (saved_node, node) in saved.nodes.iter().zip(patch.nodes.iter()) {
+			assert_eq!((&saved_node.recipe, &saved_node.name, &saved_node.values), (&node.recipe, &node.name, &node.values));
+
+			// the parameters given are kept, next to the defaults the recipe filled in
+			for (name, value) in node.parameters.iter() {
+				assert_eq!(saved_node.parameters.get(name), Some(value));
 			}
-		} else {
-			panic!("Node cookbook has not been initialized!")
 		}
+
+		// and saving the loaded patch again changes nothing
+		let (graph, _renderer) = NodeGraph::from_patch(&saved, ProcessContext::new(44100.0, 64)).unwrap();
+		assert_eq!(graph.to_patch().unwrap().to_json(), saved.to_json());
+	}
+
+	#[test]
+	fn patches_of_other_versions_are_refused() {
+		let patch = Patch { version: PATCH_FORMAT_VERSION + 1, metadata: BTreeMap::new(), nodes: Vec::new(), edges: Vec::new() };
+
+		assert!(matches!(NodeGraph::from_patch(&patch, ProcessContext::new(44100.0, 64)), Err(GraphError::InvalidPatch(_))));
+	}
+
+	#[test]
+	fn block_sizes_change_along_with_the_plan() {
+		let (mut graph, mut renderer) = new_graph();
+		let output_buffer = new_shared_output_buffer(2);
+		renderer.set_output_buffer(output_buffer.clone(), 2);
+
+		let source = graph.add_node("source", Box::new(WaveformNode::new(vec![0.5])));
+		let output = graph.add_node("output", Box::new(OutputNode::new(output_buffer.clone(), 1, 2)));
+		graph.connect(source, 0, output, 0).unwrap();
+
+		// a plan for every command but one, so the plan with the new block size fills the garbage queue
+		for i in 0..COMMAND_QUEUE_SIZE - 1 {
+			graph.add_node(&format!("filler{}", i), Box::new(SumNode::new(1)));
+			graph.commit().unwrap();
+		}
+		graph.set_block_size(128).unwrap();
+
+		renderer.update();
+
+		assert_eq!(renderer.get_context().block_size, 128);
+		assert_eq!(*output_buffer.lock().unwrap(), vec![0.5; 128 * 2]);
+	}
+
+	#[test]
+	fn edges_into_one_input_add_up_with_their_gains() {
+		let (mut graph, mut renderer) = new_graph();
+		let output_buffer = new_shared_output_buffer(1);
+		renderer.set_output_buffer(output_buffer.clone(), 1);
+
+		let mix = graph.add_node("mix", Box::new(SumNode::new(1)));
+		let output = graph.add_node("output", Box::new(OutputNode::new(output_buffer.clone(), 1, 1)));
+		graph.connect(mix, 0, output, 0).unwrap();
+
+		for (value, gain) in [(1.0, 0.5), (3.0, 2.0), (-0.25, 1.0)] {
+			let source = graph.add_node("source", Box::new(WaveformNode::new(vec![value])));
+
+			graph.connect(source, 0, mix, 0).unwrap();
+			graph.set_edge_gain(source, 0, mix, 0, gain).unwrap();
+		}
+
+		graph.commit().unwrap();
+		renderer.update();
+
+		assert_eq!(*output_buffer.lock().unwrap(), vec![0.5 + 6.0 - 0.25; 64]);
+	}
+
+	#[test]
+	fn disconnected_inputs_go_silent() {
+		let (mut graph, mut renderer) = new_graph();
+		let output_buffer = new_shared_output_buffer(1);
+		renderer.set_output_buffer(output_buffer.clone(), 1);
+
+		let source = graph.add_node("source", Box::new(WaveformNode::new(vec![0.5])));
+		let output = graph.add_node("output", Box::new(OutputNode::new(output_buffer.clone(), 1, 1)));
+		graph.connect(source, 0, output, 0).unwrap();
+
+		graph.commit().unwrap();
+		renderer.update();
+		assert_eq!(*output_buffer.lock().unwrap(), vec![0.5; 64]);
+
+		graph.disconnect(source, 0, output, 0).unwrap();
+
+		assert!(graph.nodes.get(source.0).unwrap().edges_out.is_empty());
+		assert!(graph.nodes.get(output.0).unwrap().edges_in.is_empty());
+		assert_eq!(graph.disconnect(source, 0, output, 0), Err(GraphError::EdgeNotFound { from: source, from_out_idx: 0, to: output, to_in_idx: 0 }));
+
+		graph.commit().unwrap();
+		renderer.update();
+		assert_eq!(*output_buffer.lock().unwrap(), vec![0.0; 64]);
 	}
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use crate::core::wav::{WavFormat, WavWriter};
+
+pub fn create_wav_file(path: &Path, format: WavFormat, channels: u16, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+	WavWriter::new(BufWriter::new(File::create(path)?), format, channels, sample_rate)
+}
+
+// Renders blocks as fast as it goes, without an audio device, and writes what ends up in the output buffer
+// to a WAV file. render_block is called once per block, and has to update the renderer along with anything
+// it reads from, like the audio input, and return the number of frames in the block. Rendering stops after max_seconds,
+// or earlier once stop returns true for a block of samples. Returns the number of frames written, which is always
+// a whole number of blocks. The writer should have the sample rate the graph was created with, and the channels
+// of its layout, and the output buffer should be the one the renderer clears before every block.
+pub fn render_to_wav<W: Write + Seek, R: FnMut() -> usize, F: FnMut(&[f32]) -> bool>(mut render_block: R, output_buffer: &Mutex<Vec<f32>>, mut writer: WavWriter<W>, max_seconds: f32, mut stop: F) -> io::Result<usize> {
+	let channels = usize::from(writer.channels());
+	let max_frames = (max_seconds.max(0.0) * writer.sample_rate() as f32).ceil() as usize;
+	let mut num_frames = 0;
+
+	while num_frames < max_frames {
+		let block_size = render_block();
+
+		let samples = output_buffer.lock().unwrap();
+		let samples = &samples[..block_size * channels];
+
+		writer.write_samples(samples)?;
+		num_frames += block_size;
+
+		if stop(samples) {
+			break;
+		}
+	}
+
+	writer.finish()?;
+
+	Ok(num_frames)
+}
+
+// A stop condition for render_to_wav, which ends the render once every sample
+// has stayed below the threshold for the given number of seconds
+pub fn stop_when_silent(threshold: f32, seconds: f32, sample_rate: u32, channels: usize) -> impl FnMut(&[f32]) -> bool {
+	let silent_frames_needed = (seconds * sample_rate as f32) as usize;
+	let mut silent_frames = 0;
+
+	move |samples: &[f32]| {
+		if samples.iter().all(|sample| sample.abs() < threshold) {
+			silent_frames += samples.len() / channels;
+		} else {
+			silent_frames = 0;
+		}
+
+		silent_frames >= silent_frames_needed
+	}
+}
\ No newline at end of file
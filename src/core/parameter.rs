@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use crate::core::error::GraphError;
+use crate::core::smoothing::Smoothing;
+
+// Index of a parameter in the list of descriptors a behavior advertises
+pub type ParameterId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ParameterType {
+	Float,
+	Integer,
+	Boolean
+}
+
+// Sent over the wire as a plain json number or bool
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ParameterValue {
+	Boolean(bool),
+	Integer(i64),
+	Float(f32)
+}
+
+impl ParameterValue {
+	pub fn as_f32(&self) -> f32 {
+		match self {
+			ParameterValue::Boolean(value) => if *value { 1.0 } else { 0.0 },
+			ParameterValue::Integer(value) => *value as f32,
+			ParameterValue::Float(value) => *value
+		}
+	}
+
+	pub fn as_i64(&self) -> i64 {
+		match self {
+			ParameterValue::Boolean(value) => *value as i64,
+			ParameterValue::Integer(value) => *value,
+			ParameterValue::Float(value) => *value as i64
+		}
+	}
+}
+
+// Describes a parameter that can be changed while the node is running
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ParameterDescriptor {
+	pub name: String,
+	#[serde(rename = "type")]
+	pub kind: ParameterType,
+	// inclusive bounds, none for parameters that take any value
+	pub range: Option<(f64, f64)>,
+	pub default: ParameterValue,
+	pub unit: String,
+	// how the behavior ramps to a new value, so clients know changes aren't instant
+	pub smoothing: Smoothing
+}
+
+impl ParameterDescriptor {
+	pub fn float(name: &str, range: Option<(f64, f64)>, default: f32, unit: &str) -> ParameterDescriptor {
+		ParameterDescriptor {
+			name: name.to_string(),
+			kind: ParameterType::Float,
+			range,
+			default: ParameterValue::Float(default),
+			unit: unit.to_string(),
+			smoothing: Smoothing::default()
+		}
+	}
+
+	// Switches take effect right away, there's nothing to ramp between
+	pub fn boolean(name: &str, default: bool) -> ParameterDescriptor {
+		ParameterDescriptor {
+			name: name.to_string(),
+			kind: ParameterType::Boolean,
+			range: None,
+			default: ParameterValue::Boolean(default),
+			unit: String::new(),
+			smoothing: Smoothing::None
+		}
+	}
+
+	// Checks a value against the type and range of the parameter, converting integers for float parameters
+	pub fn validate(&self, value: ParameterValue) -> Result<ParameterValue, GraphError> {
+		let value = match (self.kind, value) {
+			(ParameterType::Float, ParameterValue::Float(_)) => value,
+			(ParameterType::Float, ParameterValue::Integer(integer)) => ParameterValue::Float(integer as f32),
+			(ParameterType::Integer, ParameterValue::Integer(_)) => value,
+			(ParameterType::Boolean, ParameterValue::Boolean(_)) => value,
+			_ => return Err(GraphError::InvalidParameter { name: self.name.clone(), reason: format!("expected a value of type {:?}, got {:?}", self.kind, value) })
+		};
+
+		if let Some((min, max)) = self.range {
+			let number = match value {
+				ParameterValue::Float(float) => float as f64,
+				_ => value.as_i64() as f64
+			};
+
+			if !(min..=max).contains(&number) {
+				return Err(GraphError::InvalidParameter { name: self.name.clone(), reason: format!("{} is outside of the range {} to {}", number, min, max) });
+			}
+		}
+
+		Ok(value)
+	}
+}
\ No newline at end of file
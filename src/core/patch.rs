@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::core::node::{EdgeKind, NodeParameters};
+use crate::core::parameter::ParameterValue;
+use crate::core::error::GraphError;
+
+// Bump this whenever the format changes, and add a step to migrate() that upgrades the previous version
+pub const PATCH_FORMAT_VERSION: u64 = 1;
+
+// A saved graph. Nodes are referred to by an id that is only meaningful within the patch.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Patch {
+	pub version: u64,
+	#[serde(default)]
+	pub metadata: BTreeMap<String, String>,
+
+	pub nodes: Vec<PatchNode>,
+	pub edges: Vec<PatchEdge>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PatchNode {
+	pub id: usize,
+	pub recipe: String,
+	pub name: String,
+	#[serde(default)]
+	pub parameters: NodeParameters,
+	// values of the behavior's runtime parameters, by name
+	#[serde(default)]
+	pub values: BTreeMap<String, ParameterValue>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PatchEdge {
+	pub from: usize,
+	pub from_out_idx: usize,
+	pub to: usize,
+	pub to_in_idx: usize,
+
+	#[serde(default)]
+	pub kind: EdgeKind,
+	#[serde(default = "default_gain")]
+	pub gain: f32
+}
+
+fn default_gain() -> f32 {
+	1.0
+}
+
+impl Patch {
+	// Parses a patch of any known format version, upgrading it to the current one
+	pub fn from_json(json: &str) -> Result<Patch, GraphError> {
+		let mut value: Value = serde_json::from_str(json).map_err(|e| GraphError::InvalidPatch(e.to_string()))?;
+
+		migrate(&mut value)?;
+
+		serde_json::from_value(value).map_err(|e| GraphError::InvalidPatch(e.to_string()))
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).expect("Patches should always be serializable!")
+	}
+}
+
+fn migrate(value: &mut Value) -> Result<(), GraphError> {
+	let version = match value.get("version").and_then(|version| version.as_u64()) {
+		Some(version) => version,
+		None => return Err(GraphError::InvalidPatch(String::from("missing format version")))
+	};
+
+	if version == 0 || version > PATCH_FORMAT_VERSION {
+		return Err(GraphError::InvalidPatch(format!("unsupported format version {}, this build reads up to version {}", version, PATCH_FORMAT_VERSION)));
+	}
+
+	// Version 1 is the first format, so there is nothing to upgrade yet.
+	// Future steps go here, each one rewriting the value from version n to n + 1.
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unknown_versions_are_refused() {
+		let newer = format!(r#"{{ "version": {}, "nodes": [], "edges": [] }}"#, PATCH_FORMAT_VERSION + 1);
+
+		for json in [r#"{ "nodes": [], "edges": [] }"#, r#"{ "version": 0, "nodes": [], "edges": [] }"#, &newer] {
+			assert!(matches!(Patch::from_json(json), Err(GraphError::InvalidPatch(_))), "{}", json);
+		}
+
+		assert!(Patch::from_json(r#"{ "version": 1, "nodes": [], "edges": [] }"#).is_ok());
+	}
+}
\ No newline at end of file
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::time::Instant;
+use crate::core::arena::Arena;
+use crate::core::node::{BufferId, EdgeKind, Node, NodeBehavior, NodeId, NodeIn, NodeOut, ProcessContext};
+use crate::core::parameter::{ParameterId, ParameterValue};
+use crate::core::profile::SharedNodeTiming;
+use crate::core::workers::WorkerPool;
+
+// Level of the nodes without outputs, which are updated after every other node
+const SINK_LEVEL: usize = usize::MAX;
+
+// A node as it is executed by the renderer
+pub(crate) struct PlanNode {
+	behavior: Option<Box<dyn NodeBehavior>>,
+
+	// index of the node in the previous plan, to take its behavior from when the plans are swapped
+	carry_from: Option<usize>,
+
+	ins: Vec<NodeIn>,
+	outs: Vec<NodeOut>,
+
+	timing: SharedNodeTiming
+}
+
+impl PlanNode {
+	// Safety: the buffers the node reads and writes can't be written by anything else at the same time,
+	// which holds for the nodes of one level, see Plan::levels
+	unsafe fn update(&mut self, buffers: BufferPool, num_frames: usize){
+		let behavior = match self.behavior.as_mut() {
+			Some(behavior) => behavior,
+			None => return
+		};
+
+		for inp in self.ins.iter_mut() {
+			inp.update(|buffer_idx| buffers.get(buffer_idx));
+		}
+
+		for outp in self.outs.iter_mut() {
+			std::mem::swap(&mut outp.buffer, buffers.get_mut(outp.buffer_idx));
+		}
+
+		behavior.update(&self.ins, &mut self.outs, num_frames);
+
+		for outp in self.outs.iter_mut() {
+			std::mem::swap(&mut outp.buffer, buffers.get_mut(outp.buffer_idx));
+		}
+	}
+}
+
+// The buffer pool of a plan as the nodes of a level see it while they update on different threads.
+// Every node only writes its own outputs, and reads buffers that were written by earlier levels.
+#[derive(Clone, Copy)]
+struct BufferPool(*mut Vec<f32>);
+
+impl BufferPool {
+	unsafe fn get<'a>(self, buffer_idx: usize) -> &'a [f32] {
+		&*self.0.add(buffer_idx)
+	}
+
+	#[allow(clippy::mut_from_ref)]
+	unsafe fn get_mut<'a>(self, buffer_idx: usize) -> &'a mut Vec<f32> {
+		&mut *self.0.add(buffer_idx)
+	}
+}
+
+// The nodes of a plan, to update the ones of one level on different threads
+#[derive(Clone, Copy)]
+struct PlanNodes(*mut PlanNode);
+
+impl PlanNodes {
+	unsafe fn get_mut<'a>(self, node_idx: usize) -> &'a mut PlanNode {
+		&mut *self.0.add(node_idx)
+	}
+}
+
+// Threads only touch the nodes and buffers they were handed by Plan::process_levels
+unsafe impl Send for BufferPool {}
+unsafe impl Sync for BufferPool {}
+unsafe impl Send for PlanNodes {}
+unsafe impl Sync for PlanNodes {}
+
+// Where every node and buffer ended up in a compiled plan
+#[derive(Default)]
+pub(crate) struct PlanLayout {
+	node_slots: HashMap<NodeId, usize>,
+	buffer_slots: HashMap<BufferId, usize>,
+	// copies of output buffers as they were at the end of the previous block, read by feedback edges
+	delayed_slots: HashMap<BufferId, usize>,
+	// buffers are only carried over to a plan with the same block size
+	block_size: usize
+}
+
+impl PlanLayout {
+	pub(crate) fn get_node_slot(&self, node_id: NodeId) -> Option<usize> {
+		self.node_slots.get(&node_id).copied()
+	}
+}
+
+// Everything the renderer needs to process one block: the nodes, their buffers and the order to run them in.
+// Plans are compiled on the control thread, and only ever moved around on the render thread.
+pub(crate) struct Plan {
+	nodes: Vec<PlanNode>,
+	// the nodes to update, grouped by level. A node only depends on nodes of earlier levels,
+	// so the nodes of a level can update in any order, or at the same time.
+	order: Vec<usize>,
+	// where each level ends in order
+	level_ends: Vec<usize>,
+	// where the level of the nodes without outputs starts in order
+	sinks_start: usize,
+
+	block_size: usize,
+	buffers: Vec<Vec<f32>>,
+	// index of the buffer in the previous plan, to take the samples from when the plans are swapped
+	buffer_carry: Vec<Option<usize>>,
+
+	// pairs of (output buffer, delayed copy) to update at the end of every block
+	delayed_copies: Vec<(usize, usize)>
+}
+
+impl Plan {
+	pub(crate) fn empty() -> Plan {
+		Plan {
+			nodes: Vec::new(),
+			order: Vec::new(),
+			level_ends: Vec::new(),
+			sinks_start: 0,
+
+			block_size: 0,
+			buffers: Vec::new(),
+			buffer_carry: Vec::new(),
+
+			delayed_copies: Vec::new()
+		}
+	}
+
+	// Adds a buffer to the pool, reusing the samples of the previous plan's buffer if there is one
+	fn push_buffer(&mut self, carry: Option<usize>) -> usize {
+		// carried buffers are swapped in from the previous plan, so there's no need to allocate them
+		self.buffers.push(if carry.is_some() { Vec::new() } else { vec![0.0; self.block_size] });
+		self.buffer_carry.push(carry);
+
+		self.buffers.len() - 1
+	}
+
+	// Builds a plan for the graph, given the layout of the plan it will replace.
+	// Nodes that aren't part of sorted are kept in the plan, so their behavior survives, but are not updated.
+	pub(crate) fn compile(nodes: &Arena<Node>, sorted: &[NodeId], previous: &PlanLayout, new_behaviors: &mut HashMap<NodeId, Box<dyn NodeBehavior>>, block_size: usize) -> (Plan, PlanLayout) {
+		let mut plan = Plan::empty();
+		plan.block_size = block_size;
+
+		let mut layout = PlanLayout { block_size, ..PlanLayout::default() };
+		let can_carry = previous.block_size == block_size;
+
+		for (_, node) in nodes.iter() {
+			for buffer_id in node.out_buffers.iter() {
+				let buffer_idx = plan.push_buffer(previous.buffer_slots.get(buffer_id).copied().filter(|_| can_carry));
+				layout.buffer_slots.insert(*buffer_id, buffer_idx);
+			}
+		}
+
+		for (_, node) in nodes.iter() {
+			for edge in node.edges_in.iter().filter(|edge| edge.kind == EdgeKind::Feedback) {
+				if let Entry::Vacant(entry) = layout.delayed_slots.entry(edge.from_buffer) {
+					let delayed_idx = plan.push_buffer(previous.delayed_slots.get(&edge.from_buffer).copied().filter(|_| can_carry));
+
+					entry.insert(delayed_idx);
+					plan.delayed_copies.push((layout.buffer_slots[&edge.from_buffer], delayed_idx));
+				}
+			}
+		}
+
+		for (_, node) in nodes.iter() {
+			let ins = (0..node.info.num_ins).map(|input_idx| {
+				let sources = node.get_input_edges(input_idx).map(|edge| match edge.kind {
+					EdgeKind::Normal => (layout.buffer_slots[&edge.from_buffer], edge.gain),
+					EdgeKind::Feedback => (layout.delayed_slots[&edge.from_buffer], edge.gain)
+				}).collect();
+
+				NodeIn::new(sources, block_size)
+			}).collect();
+
+			let outs = node.out_buffers.iter().map(|buffer_id| NodeOut::new(layout.buffer_slots[buffer_id])).collect();
+
+			let behavior = new_behaviors.remove(&node.id);
+			let carry_from = if behavior.is_none() { previous.node_slots.get(&node.id).copied() } else { None };
+
+			layout.node_slots.insert(node.id, plan.nodes.len());
+
+			plan.nodes.push(PlanNode {
+				behavior,
+
+				carry_from,
+
+				ins,
+				outs,
+
+				timing: node.timing.clone()
+			});
+		}
+
+		// sorted is in topological order, so the nodes feeding a node always have their level already.
+		// Nodes without outputs, like the output nodes, only have an effect outside the graph, which could depend
+		// on their order. Nothing depends on them, so they all go last, in one level that isn't spread over threads.
+		let mut levels: HashMap<NodeId, usize> = HashMap::new();
+		for node_id in sorted.iter() {
+			let node = nodes.get(node_id.0).unwrap();
+			let level = if node.info.num_outs == 0 { SINK_LEVEL } else {
+				node.edges_in.iter()
+					.filter(|edge| edge.kind == EdgeKind::Normal)
+					.map(|edge| levels[&edge.from] + 1)
+					.max()
+					.unwrap_or(0)
+			};
+
+			levels.insert(*node_id, level);
+		}
+
+		// a stable sort, so the nodes of a level stay in topological order
+		let mut by_level: Vec<(usize, usize)> = sorted.iter().map(|node_id| (levels[node_id], layout.node_slots[node_id])).collect();
+		by_level.sort_by_key(|(level, _)| *level);
+
+		plan.order = by_level.iter().map(|(_, node_idx)| *node_idx).collect();
+
+		for (i, (level, _)) in by_level.iter().enumerate() {
+			if by_level.get(i + 1).map(|(next_level, _)| next_level) != Some(level) {
+				plan.level_ends.push(i + 1);
+			}
+		}
+
+		plan.sinks_start = by_level.iter().position(|(level, _)| *level == SINK_LEVEL).unwrap_or(by_level.len());
+
+		(plan, layout)
+	}
+
+	// Moves the node behaviors and buffer contents that carry over from the previous plan.
+	// This doesn't allocate or free anything, so it is safe to do on the render thread.
+	pub(crate) fn take_state_from(&mut self, previous: &mut Plan){
+		for node in self.nodes.iter_mut() {
+			if let Some(previous_idx) = node.carry_from {
+				node.behavior = previous.nodes[previous_idx].behavior.take();
+			}
+		}
+
+		for (buffer, carry) in self.buffers.iter_mut().zip(self.buffer_carry.iter()) {
+			if let Some(previous_idx) = carry {
+				std::mem::swap(buffer, &mut previous.buffers[*previous_idx]);
+			}
+		}
+	}
+
+	pub(crate) fn set_parameter(&mut self, node_idx: usize, id: ParameterId, value: ParameterValue){
+		if let Some(behavior) = self.nodes.get_mut(node_idx).and_then(|node| node.behavior.as_mut()) {
+			behavior.set_parameter(id, value);
+		}
+	}
+
+	pub(crate) fn prepare(&mut self, context: &ProcessContext){
+		for node in self.nodes.iter_mut() {
+			if let Some(behavior) = node.behavior.as_mut() {
+				behavior.prepare(context);
+			}
+		}
+	}
+
+	// Profiling is checked once per block, so the nodes run exactly the same way when it's off.
+	// With workers, the levels with more than one node are spread over them.
+	pub(crate) fn process(&mut self, is_profiling: bool, workers: Option<&mut WorkerPool>){
+		if is_profiling {
+			self.process_levels(workers, |node, buffers, num_frames| {
+				let started = Instant::now();
+
+				unsafe { node.update(buffers, num_frames) };
+
+				node.timing.record(started.elapsed());
+			});
+		} else {
+			self.process_levels(workers, |node, buffers, num_frames| unsafe { node.update(buffers, num_frames) });
+		}
+
+		for (buffer_idx, delayed_idx) in self.delayed_copies.iter() {
+			let (buffer, delayed) = get_pair_mut(&mut self.buffers, *buffer_idx, *delayed_idx);
+			delayed.copy_from_slice(buffer);
+		}
+	}
+
+	fn process_levels<F: Fn(&mut PlanNode, BufferPool, usize) + Sync>(&mut self, mut workers: Option<&mut WorkerPool>, update: F){
+		let nodes = PlanNodes(self.nodes.as_mut_ptr());
+		let buffers = BufferPool(self.buffers.as_mut_ptr());
+		let num_frames = self.block_size;
+
+		let mut level_start = 0;
+		for level_end in self.level_ends.iter() {
+			let is_sinks = level_start >= self.sinks_start;
+			let level = &self.order[level_start..*level_end];
+			level_start = *level_end;
+
+			match workers.as_mut() {
+				Some(workers) if level.len() > 1 && !is_sinks => {
+					// every node of the level is handed out exactly once
+					workers.run(level.len(), &|i| update(unsafe { nodes.get_mut(level[i]) }, buffers, num_frames));
+				},
+				_ => {
+					for node_idx in level.iter() {
+						update(unsafe { nodes.get_mut(*node_idx) }, buffers, num_frames);
+					}
+				}
+			}
+		}
+	}
+
+	// Called on the control thread for plans that have been swapped out.
+	// Any behavior still in the plan belongs to a node that was removed from the graph.
+	pub(crate) fn before_drop(&mut self){
+		for node in self.nodes.iter_mut() {
+			if let Some(behavior) = node.behavior.as_mut() {
+				behavior.before_drop();
+			}
+		}
+	}
+}
+
+// Borrows two different buffers of the pool at once
+fn get_pair_mut(buffers: &mut [Vec<f32>], a: usize, b: usize) -> (&mut Vec<f32>, &mut Vec<f32>) {
+	if a < b {
+		let (left, right) = buffers.split_at_mut(b);
+		(&mut left[a], &mut right[0])
+	} else {
+		let (left, right) = buffers.split_at_mut(a);
+		(&mut right[0], &mut left[b])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Instant;
+	use crate::behavior::basic::{OutputNode, SumNode};
+	use crate::behavior::oscillator::{OscillatorNode, OscillatorShape};
+	use crate::behavior::waveform::{SinNode, WaveformNode};
+	use crate::core::node::{NodeBehavior, ProcessContext};
+	use crate::core::node_graph::NodeGraph;
+	use crate::core::renderer::{new_shared_output_buffer, Renderer, SharedOutputBuffer};
+
+	const NUM_GROUPS: usize = 8;
+
+	// Oscillators of every kind, summed in groups that are mixed down to a stereo output, with a feedback edge
+	// from the mix back into the first group. That makes levels of very different widths, and a delayed buffer.
+	fn build_oscillator_bank(num_oscillators: usize, num_threads: usize) -> (NodeGraph, Renderer, SharedOutputBuffer) {
+		let (mut graph, mut renderer) = NodeGraph::new(ProcessContext::new(44100.0, 256));
+		let output_buffer = new_shared_output_buffer(2);
+
+		let groups: Vec<_> = (0..NUM_GROUPS).map(|i| graph.add_node(&format!("group{}", i), Box::new(SumNode::new(1)))).collect();
+		let mix = graph.add_node("mix", Box::new(SumNode::new(1)));
+		let output = graph.add_node("output", Box::new(OutputNode::new(output_buffer.clone(), 2, 2)));
+
+		for i in 0..num_oscillators {
+			let freq = graph.add_node(&format!("freq{}", i), Box::new(WaveformNode::new(vec![55.0 + 17.3 * i as f32])));
+			let oscillator: Box<dyn NodeBehavior> = match i % 4 {
+				0 => Box::new(OscillatorNode::new(OscillatorShape::Saw)),
+				1 => Box::new(OscillatorNode::new(OscillatorShape::Pulse)),
+				2 => Box::new(OscillatorNode::new(OscillatorShape::Triangle)),
+				_ => Box::new(SinNode::new())
+			};
+			let oscillator = graph.add_node(&format!("oscillator{}", i), oscillator);
+
+			graph.connect(freq, 0, oscillator, 0).unwrap();
+			graph.connect(oscillator, 0, groups[i % NUM_GROUPS], 0).unwrap();
+			graph.set_edge_gain(oscillator, 0, groups[i % NUM_GROUPS], 0, 1.0 / num_oscillators as f32).unwrap();
+		}
+
+		for group in groups.iter() {
+			graph.connect(*group, 0, mix, 0).unwrap();
+		}
+
+		graph.connect_feedback(mix, 0, groups[0], 0).unwrap();
+		graph.set_edge_gain(mix, 0, groups[0], 0, 0.5).unwrap();
+
+		graph.connect(mix, 0, output, 0).unwrap();
+		graph.connect(groups[1], 0, output, 1).unwrap();
+
+		graph.commit().unwrap();
+
+		renderer.set_output_buffer(output_buffer.clone(), 2);
+		renderer.set_num_threads(num_threads, false);
+
+		(graph, renderer, output_buffer)
+	}
+
+	fn render(num_oscillators: usize, num_threads: usize, num_blocks: usize) -> Vec<f32> {
+		let (_graph, mut renderer, output_buffer) = build_oscillator_bank(num_oscillators, num_threads);
+		let mut samples = Vec::new();
+
+		for _ in 0..num_blocks {
+			renderer.update();
+			samples.extend_from_slice(&output_buffer.lock().unwrap());
+		}
+
+		samples
+	}
+
+	#[test]
+	fn threads_render_the_same_as_one() {
+		let serial = render(64, 1, 100);
+
+		assert!(serial.iter().any(|sample| *sample != 0.0));
+
+		for num_threads in [2, 3, 8] {
+			let parallel = render(64, num_threads, 100);
+
+			// bit for bit, not just close
+			assert!(serial.iter().map(|sample| sample.to_bits()).eq(parallel.iter().map(|sample| sample.to_bits())), "{} threads rendered something else than 1", num_threads);
+		}
+	}
+
+	// Run with cargo test --release -- --ignored --nocapture to see how rendering scales with the cores of the machine
+	#[test]
+	#[ignore]
+	fn bench_oscillator_bank() {
+		const NUM_OSCILLATORS: usize = 512;
+		const NUM_BLOCKS: usize = 2000;
+
+		let num_cores = std::thread::available_parallelism().map_or(1, |num_cores| num_cores.get());
+		let mut serial_secs = 0.0;
+
+		for num_threads in (1..=num_cores).filter(|num_threads| *num_threads == 1 || num_threads.is_power_of_two() || *num_threads == num_cores) {
+			let (_graph, mut renderer, _output_buffer) = build_oscillator_bank(NUM_OSCILLATORS, num_threads);
+
+			// the first blocks take the workers out of their sleep
+			for _ in 0..10 {
+				renderer.update();
+			}
+
+			let started = Instant::now();
+			for _ in 0..NUM_BLOCKS {
+				renderer.update();
+			}
+			let secs = started.elapsed().as_secs_f64();
+
+			if num_threads == 1 {
+				serial_secs = secs;
+			}
+
+			let audio_secs = (NUM_BLOCKS * 256) as f64 / 44100.0;
+			println!("{} oscillators on {} thread(s): {:.0}x real time, {:.2}x the speed of 1 thread", NUM_OSCILLATORS, num_threads, audio_secs / secs, serial_secs / secs);
+		}
+	}
+}
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+// Time spent in the update of one node, recorded by the renderer while profiling is on.
+// Every node of the graph has its own, shared with the plans it is compiled into.
+pub struct NodeTiming {
+	blocks: AtomicU64,
+	total_ns: AtomicU64,
+	min_ns: AtomicU64,
+	max_ns: AtomicU64
+}
+
+pub type SharedNodeTiming = Arc<NodeTiming>;
+
+// The timings of a node since profiling was turned on, as sent to websocket clients
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeProfile {
+	pub blocks: u64,
+	pub min_us: f32,
+	pub avg_us: f32,
+	pub max_us: f32,
+	// average time over the duration of a block. All nodes together have to stay well below 1 to play in real time.
+	pub budget_share: f32
+}
+
+impl NodeTiming {
+	pub fn new() -> SharedNodeTiming {
+		Arc::new(NodeTiming {
+			blocks: AtomicU64::new(0),
+			total_ns: AtomicU64::new(0),
+			min_ns: AtomicU64::new(u64::MAX),
+			max_ns: AtomicU64::new(0)
+		})
+	}
+
+	pub fn record(&self, duration: Duration){
+		let duration_ns = duration.as_nanos() as u64;
+
+		self.blocks.fetch_add(1, Ordering::Relaxed);
+		self.total_ns.fetch_add(duration_ns, Ordering::Relaxed);
+		self.min_ns.fetch_min(duration_ns, Ordering::Relaxed);
+		self.max_ns.fetch_max(duration_ns, Ordering::Relaxed);
+	}
+
+	pub fn reset(&self){
+		self.blocks.store(0, Ordering::Relaxed);
+		self.total_ns.store(0, Ordering::Relaxed);
+		self.min_ns.store(u64::MAX, Ordering::Relaxed);
+		self.max_ns.store(0, Ordering::Relaxed);
+	}
+
+	// block_us is how long a block lasts, which is the budget the whole graph has to render it in
+	pub fn get_profile(&self, block_us: f32) -> NodeProfile {
+		let blocks = self.blocks.load(Ordering::Relaxed);
+
+		if blocks == 0 {
+			return NodeProfile { blocks, min_us: 0.0, avg_us: 0.0, max_us: 0.0, budget_share: 0.0 };
+		}
+
+		let avg_us = self.total_ns.load(Ordering::Relaxed) as f32 / blocks as f32 / 1000.0;
+
+		NodeProfile {
+			blocks,
+			min_us: self.min_ns.load(Ordering::Relaxed) as f32 / 1000.0,
+			avg_us,
+			max_us: self.max_ns.load(Ordering::Relaxed) as f32 / 1000.0,
+			budget_share: if block_us > 0.0 { avg_us / block_us } else { 0.0 }
+		}
+	}
+}
\ No newline at end of file
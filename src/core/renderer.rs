@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+use crate::core::plan::Plan;
+use crate::core::node::{ProcessContext, MAX_BLOCK_SIZE};
+use crate::core::parameter::{ParameterId, ParameterValue};
+use crate::core::workers::WorkerPool;
+
+extern crate ringbuf;
+
+pub(crate) const COMMAND_QUEUE_SIZE: usize = 64;
+
+// Interleaved samples written by an output node, read back by whoever drives the graph.
+// Holds exactly one block, whatever the block size currently is.
+pub type SharedOutputBuffer = Arc<Mutex<Vec<f32>>>;
+
+pub fn new_shared_output_buffer(channels: usize) -> SharedOutputBuffer {
+	// room for the largest block, so changing the block size never allocates on the render thread
+	Arc::new(Mutex::new(Vec::with_capacity(MAX_BLOCK_SIZE * channels)))
+}
+
+pub(crate) enum RenderCommand {
+	// a new context comes along with the plan it was compiled for, so that plan never renders a block with the old one
+	SwapPlan { plan: Box<Plan>, context: Option<ProcessContext> },
+	// node_idx is the node's slot in the plan that is current when the command is applied
+	SetParameter { node_idx: usize, id: ParameterId, value: ParameterValue },
+	Prepare(ProcessContext),
+	// times every node update from the next block on, into the timings of the nodes
+	SetProfiling(bool)
+}
+
+// Runs the plans compiled by a NodeGraph. The renderer lives on the render thread, and only talks to
+// the graph through lock-free queues: commands come in, and swapped out plans go back to be dropped.
+pub struct Renderer {
+	plan: Box<Plan>,
+	context: ProcessContext,
+	is_profiling: bool,
+	// threads that update the nodes of a level along with the render thread, none to update every node on it
+	workers: Option<WorkerPool>,
+	// the buffer the output nodes write to, and its number of channels
+	output: Option<(SharedOutputBuffer, usize)>,
+
+	commands: ringbuf::Consumer<RenderCommand>,
+	garbage: ringbuf::Producer<Box<Plan>>
+}
+
+impl Renderer {
+	pub(crate) fn new(context: ProcessContext, commands: ringbuf::Consumer<RenderCommand>, garbage: ringbuf::Producer<Box<Plan>>) -> Renderer {
+		Renderer {
+			plan: Box::new(Plan::empty()),
+			context,
+			is_profiling: false,
+			workers: None,
+			output: None,
+
+			commands,
+			garbage
+		}
+	}
+
+	fn apply_commands(&mut self){
+		// every command hands back at most one plan, so only take a command when there's room to return it
+		while !self.garbage.is_full() {
+			match self.commands.pop() {
+				Some(RenderCommand::SwapPlan { mut plan, context }) => {
+					plan.take_state_from(&mut self.plan);
+
+					if let Some(context) = context {
+						plan.prepare(&context);
+						self.context = context;
+					}
+
+					let old_plan = std::mem::replace(&mut self.plan, plan);
+
+					// can't fail, the garbage queue was checked for room above
+					let _ = self.garbage.push(old_plan);
+				},
+				Some(RenderCommand::SetParameter { node_idx, id, value }) => {
+					self.plan.set_parameter(node_idx, id, value);
+				},
+				Some(RenderCommand::Prepare(context)) => {
+					self.plan.prepare(&context);
+					self.context = context;
+				},
+				Some(RenderCommand::SetProfiling(is_profiling)) => {
+					self.is_profiling = is_profiling;
+				},
+				None => break
+			}
+		}
+	}
+
+	// The context of the last prepare, which the next block is rendered with unless a change is still queued up
+	pub fn get_context(&self) -> ProcessContext {
+		self.context
+	}
+
+	// How many threads render the graph, counting the render thread. This starts the other threads,
+	// so it should be called before rendering starts. Real-time threads are for playing, see WorkerPool::new.
+	pub fn set_num_threads(&mut self, num_threads: usize, is_realtime: bool){
+		self.workers = if num_threads > 1 { Some(WorkerPool::new(num_threads - 1, is_realtime)) } else { None };
+	}
+
+	pub fn get_num_threads(&self) -> usize {
+		1 + self.workers.as_ref().map_or(0, |workers| workers.num_workers())
+	}
+
+	// Has every block start out as silence in the output buffer, so it holds a whole block
+	// after every update, whether the graph has an output node or not
+	pub fn set_output_buffer(&mut self, output_buffer: SharedOutputBuffer, channels: usize){
+		self.output = Some((output_buffer, channels));
+	}
+
+	pub fn update(&mut self){
+		self.apply_commands();
+
+		if let Some((output_buffer, channels)) = &self.output {
+			let mut samples = output_buffer.lock().unwrap();
+
+			samples.clear();
+			samples.resize(self.context.block_size * channels, 0.0);
+		}
+
+		self.plan.process(self.is_profiling, self.workers.as_mut());
+	}
+}
\ No newline at end of file
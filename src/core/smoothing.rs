@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use crate::core::node::DEFAULT_SAMPLE_RATE;
+
+pub const DEFAULT_SMOOTHING_TIME: f32 = 0.02;
+
+// How a parameter moves towards a new value, with times in seconds
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "mode")]
+pub enum Smoothing {
+	None,
+	// reaches the target after exactly the given time
+	Linear { time: f32 },
+	// exponential approach, the time is how long it takes to cover ~63% of the distance
+	OnePole { time: f32 }
+}
+
+impl Default for Smoothing {
+	fn default() -> Smoothing {
+		Smoothing::Linear { time: DEFAULT_SMOOTHING_TIME }
+	}
+}
+
+// A value that ramps to its target one sample at a time. Behaviors keep one for every parameter
+// that feeds into the signal, and call next() for every sample of the block in update().
+// Changes arrive between blocks, so a ramp always starts on the first sample of the next block.
+#[derive(Clone, Debug)]
+pub struct SmoothedValue {
+	smoothing: Smoothing,
+	sample_rate: f32,
+
+	current: f32,
+	target: f32,
+
+	// linear ramps: amount to add every sample, and how many samples are left
+	step: f32,
+	remaining: usize,
+	// one-pole ramps: how much of the distance to the target is kept every sample
+	coefficient: f32
+}
+
+impl SmoothedValue {
+	// Ramp times are worked out for DEFAULT_SAMPLE_RATE until set_sample_rate() is called
+	pub fn new(value: f32, smoothing: Smoothing) -> SmoothedValue {
+		let mut smoothed = SmoothedValue {
+			smoothing,
+			sample_rate: DEFAULT_SAMPLE_RATE,
+
+			current: value,
+			target: value,
+
+			step: 0.0,
+			remaining: 0,
+			coefficient: 0.0
+		};
+
+		smoothed.set_sample_rate(DEFAULT_SAMPLE_RATE);
+
+		smoothed
+	}
+
+	pub fn set_sample_rate(&mut self, sample_rate: f32){
+		self.sample_rate = sample_rate;
+
+		self.coefficient = match self.smoothing {
+			Smoothing::OnePole { time } if time > 0.0 => (-1.0 / (time * sample_rate)).exp(),
+			_ => 0.0
+		};
+	}
+
+	pub fn set_target(&mut self, target: f32){
+		self.target = target;
+
+		match self.smoothing {
+			Smoothing::Linear { time } => {
+				self.remaining = (time * self.sample_rate).round() as usize;
+
+				if self.remaining == 0 {
+					self.current = target;
+				} else {
+					self.step = (target - self.current) / self.remaining as f32;
+				}
+			},
+			Smoothing::OnePole { .. } => {},
+			Smoothing::None => {
+				self.current = target;
+			}
+		}
+	}
+
+	// The value the ramp is heading to, which is what the parameter was last set to
+	pub fn target(&self) -> f32 {
+		self.target
+	}
+
+	pub fn is_smoothing(&self) -> bool {
+		self.current != self.target
+	}
+
+	pub fn next(&mut self) -> f32 {
+		if !self.is_smoothing() {
+			return self.current;
+		}
+
+		match self.smoothing {
+			Smoothing::Linear { .. } => {
+				self.remaining -= 1;
+
+				// land exactly on the target instead of accumulating rounding errors
+				self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+			},
+			Smoothing::OnePole { .. } => {
+				let next = self.target + (self.current - self.target) * self.coefficient;
+
+				// close to the target the steps get smaller than f32 can represent, so snap once progress stops
+				self.current = if next == self.current || (next - self.target).abs() < 1e-6 { self.target } else { next };
+			},
+			Smoothing::None => {
+				self.current = self.target;
+			}
+		}
+
+		self.current
+	}
+}
\ No newline at end of file
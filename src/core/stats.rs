@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+// Counters and timings of the audio callbacks and the graph thread. Everything is an atomic, so recording
+// never blocks or allocates on the threads that can't afford it. The control thread reads them back
+// with take_snapshot(), which starts a new period for everything but the xrun totals.
+#[derive(Default)]
+pub struct EngineStats {
+	// output callbacks that found fewer samples in the ring buffer than they needed, and played silence
+	underruns: AtomicU64,
+	// times captured input was thrown away, because it piled up faster than the graph read it
+	overruns: AtomicU64,
+
+	callbacks: AtomicU64,
+	callback_time_ns: AtomicU64,
+	callback_time_max_ns: AtomicU64,
+	callback_interval_max_ns: AtomicU64,
+
+	blocks: AtomicU64,
+	rendered_frames: AtomicU64,
+	render_time_ns: AtomicU64,
+	render_time_max_ns: AtomicU64
+}
+
+pub type SharedEngineStats = Arc<EngineStats>;
+
+// What was recorded over one period, as sent to websocket clients
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EngineStatsSnapshot {
+	// since the engine started
+	pub underruns: u64,
+	pub overruns: u64,
+
+	// since the previous snapshot
+	pub callbacks: u64,
+	pub callback_avg_us: f32,
+	pub callback_max_us: f32,
+	// the longest wait between two output callbacks
+	pub callback_interval_max_us: f32,
+
+	pub blocks: u64,
+	pub render_avg_us: f32,
+	pub render_max_us: f32,
+	// time spent rendering over the duration of what was rendered. Above 1 the patch is too heavy for real time.
+	pub render_load: f32
+}
+
+impl EngineStats {
+	pub fn new() -> SharedEngineStats {
+		Arc::new(EngineStats::default())
+	}
+
+	pub fn record_underrun(&self){
+		self.underruns.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn record_overrun(&self){
+		self.overruns.fetch_add(1, Ordering::Relaxed);
+	}
+
+	// duration is the time spent in the callback, interval the time since the previous one started
+	pub fn record_callback(&self, duration: Duration, interval: Duration){
+		let duration_ns = duration.as_nanos() as u64;
+
+		self.callbacks.fetch_add(1, Ordering::Relaxed);
+		self.callback_time_ns.fetch_add(duration_ns, Ordering::Relaxed);
+		self.callback_time_max_ns.fetch_max(duration_ns, Ordering::Relaxed);
+		self.callback_interval_max_ns.fetch_max(interval.as_nanos() as u64, Ordering::Relaxed);
+	}
+
+	pub fn record_render(&self, duration: Duration, num_frames: usize){
+		let duration_ns = duration.as_nanos() as u64;
+
+		self.blocks.fetch_add(1, Ordering::Relaxed);
+		self.rendered_frames.fetch_add(num_frames as u64, Ordering::Relaxed);
+		self.render_time_ns.fetch_add(duration_ns, Ordering::Relaxed);
+		self.render_time_max_ns.fetch_max(duration_ns, Ordering::Relaxed);
+	}
+
+	// Reads everything recorded since the previous snapshot and resets it. A callback that lands in between
+	// can end up split over two periods, which is fine for statistics.
+	pub fn take_snapshot(&self, sample_rate: u32) -> EngineStatsSnapshot {
+		let callbacks = self.callbacks.swap(0, Ordering::Relaxed);
+		let callback_time_ns = self.callback_time_ns.swap(0, Ordering::Relaxed);
+
+		let blocks = self.blocks.swap(0, Ordering::Relaxed);
+		let rendered_frames = self.rendered_frames.swap(0, Ordering::Relaxed);
+		let render_time_ns = self.render_time_ns.swap(0, Ordering::Relaxed);
+
+		let rendered_ns = rendered_frames as f64 * 1e9 / f64::from(sample_rate);
+
+		EngineStatsSnapshot {
+			underruns: self.underruns.load(Ordering::Relaxed),
+			overruns: self.overruns.load(Ordering::Relaxed),
+
+			callbacks,
+			callback_avg_us: average_us(callback_time_ns, callbacks),
+			callback_max_us: self.callback_time_max_ns.swap(0, Ordering::Relaxed) as f32 / 1000.0,
+			callback_interval_max_us: self.callback_interval_max_ns.swap(0, Ordering::Relaxed) as f32 / 1000.0,
+
+			blocks,
+			render_avg_us: average_us(render_time_ns, blocks),
+			render_max_us: self.render_time_max_ns.swap(0, Ordering::Relaxed) as f32 / 1000.0,
+			render_load: if rendered_ns > 0.0 { (render_time_ns as f64 / rendered_ns) as f32 } else { 0.0 }
+		}
+	}
+}
+
+fn average_us(total_ns: u64, count: u64) -> f32 {
+	if count == 0 {
+		0.0
+	} else {
+		total_ns as f32 / count as f32 / 1000.0
+	}
+}
\ No newline at end of file
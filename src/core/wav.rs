@@ -0,0 +1,240 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WavFormat {
+	Int16,
+	Int24,
+	Float32
+}
+
+impl WavFormat {
+	pub fn bits_per_sample(&self) -> u16 {
+		match self {
+			WavFormat::Int16 => 16,
+			WavFormat::Int24 => 24,
+			WavFormat::Float32 => 32
+		}
+	}
+
+	// the format tag in the fmt chunk: 1 is integer PCM, 3 is IEEE float
+	fn format_tag(&self) -> u16 {
+		match self {
+			WavFormat::Float32 => 3,
+			_ => 1
+		}
+	}
+}
+
+// Streams interleaved samples into a WAV file. The sizes in the header are only known at the end,
+// so they are written as zero first and filled in by finish().
+pub struct WavWriter<W: Write + Seek> {
+	inner: W,
+	format: WavFormat,
+	channels: u16,
+	sample_rate: u32,
+
+	num_samples: u64,
+
+	riff_size_pos: u64,
+	// only float files have a fact chunk
+	fact_frames_pos: Option<u64>,
+	data_size_pos: u64
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+	pub fn new(mut inner: W, format: WavFormat, channels: u16, sample_rate: u32) -> io::Result<WavWriter<W>> {
+		let bytes_per_sample = format.bits_per_sample() / 8;
+		let block_align = bytes_per_sample * channels;
+		let is_float = format == WavFormat::Float32;
+
+		inner.write_all(b"RIFF")?;
+		let riff_size_pos = inner.stream_position()?;
+		inner.write_all(&0u32.to_le_bytes())?;
+		inner.write_all(b"WAVE")?;
+
+		inner.write_all(b"fmt ")?;
+		inner.write_all(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes())?;
+		inner.write_all(&format.format_tag().to_le_bytes())?;
+		inner.write_all(&channels.to_le_bytes())?;
+		inner.write_all(&sample_rate.to_le_bytes())?;
+		inner.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+		inner.write_all(&block_align.to_le_bytes())?;
+		inner.write_all(&format.bits_per_sample().to_le_bytes())?;
+
+		let fact_frames_pos = if is_float {
+			// no extra format information
+			inner.write_all(&0u16.to_le_bytes())?;
+
+			inner.write_all(b"fact")?;
+			inner.write_all(&4u32.to_le_bytes())?;
+			let pos = inner.stream_position()?;
+			inner.write_all(&0u32.to_le_bytes())?;
+
+			Some(pos)
+		} else {
+			None
+		};
+
+		inner.write_all(b"data")?;
+		let data_size_pos = inner.stream_position()?;
+		inner.write_all(&0u32.to_le_bytes())?;
+
+		Ok(WavWriter {
+			inner,
+			format,
+			channels,
+			sample_rate,
+
+			num_samples: 0,
+
+			riff_size_pos,
+			fact_frames_pos,
+			data_size_pos
+		})
+	}
+
+	pub fn channels(&self) -> u16 {
+		self.channels
+	}
+
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	// Samples are clamped to -1..1 for the integer formats
+	pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+		for sample in samples.iter() {
+			match self.format {
+				WavFormat::Int16 => {
+					let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+					self.inner.write_all(&value.to_le_bytes())?;
+				},
+				WavFormat::Int24 => {
+					let value = (sample.clamp(-1.0, 1.0) * 8388607.0).round() as i32;
+					self.inner.write_all(&value.to_le_bytes()[0..3])?;
+				},
+				WavFormat::Float32 => {
+					self.inner.write_all(&sample.to_le_bytes())?;
+				}
+			}
+		}
+
+		self.num_samples += samples.len() as u64;
+
+		Ok(())
+	}
+
+	// Fills in the sizes in the header and returns the underlying writer
+	pub fn finish(mut self) -> io::Result<W> {
+		let data_size = self.num_samples * u64::from(self.format.bits_per_sample() / 8);
+
+		// chunks have to be an even number of bytes long
+		if data_size % 2 == 1 {
+			self.inner.write_all(&[0])?;
+		}
+
+		let end = self.inner.stream_position()?;
+		let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "WAV files can't be larger than 4 GiB");
+
+		self.inner.seek(SeekFrom::Start(self.riff_size_pos))?;
+		self.inner.write_all(&u32::try_from(end - self.riff_size_pos - 4).map_err(|_| too_large())?.to_le_bytes())?;
+
+		if let Some(fact_frames_pos) = self.fact_frames_pos {
+			let num_frames = self.num_samples / u64::from(self.channels);
+
+			self.inner.seek(SeekFrom::Start(fact_frames_pos))?;
+			self.inner.write_all(&u32::try_from(num_frames).map_err(|_| too_large())?.to_le_bytes())?;
+		}
+
+		self.inner.seek(SeekFrom::Start(self.data_size_pos))?;
+		self.inner.write_all(&u32::try_from(data_size).map_err(|_| too_large())?.to_le_bytes())?;
+
+		self.inner.seek(SeekFrom::Start(end))?;
+		self.inner.flush()?;
+
+		Ok(self.inner)
+	}
+}
+
+// The contents of a WAV file, converted to interleaved f32 samples
+pub struct WavData {
+	pub channels: u16,
+	pub sample_rate: u32,
+	pub samples: Vec<f32>
+}
+
+// Reads the formats WavWriter writes, from plain or extensible fmt chunks
+pub fn read_wav<R: Read>(mut reader: R) -> io::Result<WavData> {
+	let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+
+	let mut header = [0u8; 12];
+	reader.read_exact(&mut header)?;
+
+	if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+		return Err(invalid("not a RIFF WAVE file"));
+	}
+
+	let mut format_info: Option<(WavFormat, u16, u32)> = None;
+
+	loop {
+		let mut chunk_header = [0u8; 8];
+		reader.read_exact(&mut chunk_header)?;
+
+		let chunk_size = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as usize;
+		let mut chunk = vec![0u8; chunk_size + chunk_size % 2];
+		reader.read_exact(&mut chunk)?;
+		chunk.truncate(chunk_size);
+
+		match &chunk_header[0..4] {
+			b"fmt " => {
+				if chunk.len() < 16 {
+					return Err(invalid("fmt chunk is too short"));
+				}
+
+				let read_u16 = |pos: usize| u16::from_le_bytes([chunk[pos], chunk[pos + 1]]);
+
+				let mut format_tag = read_u16(0);
+				let channels = read_u16(2);
+				let sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+				let bits_per_sample = read_u16(14);
+
+				// extensible files keep the actual format tag at the start of the sub format guid
+				if format_tag == 0xFFFE && chunk.len() >= 26 {
+					format_tag = read_u16(24);
+				}
+
+				let format = match (format_tag, bits_per_sample) {
+					(1, 16) => WavFormat::Int16,
+					(1, 24) => WavFormat::Int24,
+					(3, 32) => WavFormat::Float32,
+					_ => return Err(invalid(&format!("unsupported sample format {} with {} bits", format_tag, bits_per_sample)))
+				};
+
+				if channels == 0 {
+					return Err(invalid("file has no channels"));
+				}
+
+				format_info = Some((format, channels, sample_rate));
+			},
+			b"data" => {
+				let (format, channels, sample_rate) = format_info.ok_or_else(|| invalid("data chunk comes before the fmt chunk"))?;
+
+				let samples = match format {
+					WavFormat::Int16 => chunk.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32).collect(),
+					WavFormat::Int24 => chunk.chunks_exact(3).map(|bytes| {
+						// shift into the top of an i32 to get the sign right
+						(i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as f32 / 8388607.0
+					}).collect(),
+					WavFormat::Float32 => chunk.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+				};
+
+				return Ok(WavData {
+					channels,
+					sample_rate,
+					samples
+				});
+			},
+			_ => {}
+		}
+	}
+}
\ No newline at end of file
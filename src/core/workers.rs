@@ -0,0 +1,206 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a thread busy waits for work, or for the others to finish theirs, before giving up its core.
+// Levels follow each other within microseconds, while the wait for the next block can be milliseconds long.
+const SPIN_TIME: Duration = Duration::from_micros(50);
+
+// The real-time priority of the threads that render the graph while playing. It's below what audio servers
+// and drivers run their own threads at, so the device callback still comes first, and above everything else.
+#[cfg(unix)]
+const REALTIME_PRIORITY: i32 = 50;
+
+// A task as it is handed to the workers. The lifetime is erased, run() makes sure it outlives every call.
+type Task = *const (dyn Fn(usize) + Sync);
+
+struct PoolState {
+	// the epoch in the high 32 bits, the number of items left to claim in the low 32 bits.
+	// Claiming an item checks the epoch along with it, so a worker that is late never claims from another task.
+	claims: AtomicU64,
+	// items claimed but not done yet, plus the ones still to claim
+	pending: AtomicUsize,
+
+	// only written by run() while no item can be claimed, and only read after claiming one
+	task: UnsafeCell<Option<(Task, usize)>>,
+
+	num_sleeping: AtomicUsize,
+	is_stopping: AtomicBool
+}
+
+// The task is only ever read by whoever successfully claimed an item of it, see PoolState
+unsafe impl Send for PoolState {}
+unsafe impl Sync for PoolState {}
+
+impl PoolState {
+	// Claims and runs items of the current task until there are none left. Returns whether any item was run.
+	fn work(&self) -> bool {
+		let mut has_worked = false;
+
+		loop {
+			let claims = self.claims.load(Ordering::SeqCst);
+
+			if claims as u32 == 0 {
+				return has_worked;
+			}
+
+			if self.claims.compare_exchange(claims, claims - 1, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+				continue;
+			}
+
+			// the claim succeeded, so the task is the one of this epoch, and stays alive until the item is done
+			let (task, len) = unsafe { (*self.task.get()).unwrap() };
+			unsafe { (*task)(len - claims as u32 as usize) };
+
+			self.pending.fetch_sub(1, Ordering::Release);
+			has_worked = true;
+		}
+	}
+
+	fn has_work(&self) -> bool {
+		self.claims.load(Ordering::SeqCst) as u32 != 0
+	}
+}
+
+// A fixed set of threads that help the render thread with the items of a task, such as the nodes of one level
+// of the graph. The threads are started up front, and wait for work by spinning for a while and then parking,
+// so handing out a task never allocates, and only makes a system call to wake up threads that went to sleep.
+pub(crate) struct WorkerPool {
+	state: Arc<PoolState>,
+	threads: Vec<thread::JoinHandle<()>>,
+	epoch: u32
+}
+
+impl WorkerPool {
+	// Real-time workers are only worth it while playing, offline renders leave the rest of the system alone
+	pub(crate) fn new(num_workers: usize, is_realtime: bool) -> WorkerPool {
+		let state = Arc::new(PoolState {
+			claims: AtomicU64::new(0),
+			pending: AtomicUsize::new(0),
+
+			task: UnsafeCell::new(None),
+
+			num_sleeping: AtomicUsize::new(0),
+			is_stopping: AtomicBool::new(false)
+		});
+
+		let threads = (0..num_workers).map(|worker_idx| {
+			let state = state.clone();
+
+			thread::Builder::new().name(format!("iannis-worker-{}", worker_idx)).spawn(move || {
+				while !state.is_stopping.load(Ordering::SeqCst) {
+					if state.work() {
+						continue;
+					}
+
+					let spin_until = Instant::now() + SPIN_TIME;
+					while !state.has_work() && Instant::now() < spin_until {
+						std::hint::spin_loop();
+					}
+
+					if state.has_work() {
+						continue;
+					}
+
+					// run() checks for sleeping workers after handing out a task, so either it sees this one
+					// and wakes it up, or the task is seen here before parking
+					state.num_sleeping.fetch_add(1, Ordering::SeqCst);
+
+					if !state.has_work() && !state.is_stopping.load(Ordering::SeqCst) {
+						thread::park();
+					}
+
+					state.num_sleeping.fetch_sub(1, Ordering::SeqCst);
+				}
+			}).expect("Couldn't start a worker thread!")
+		}).collect::<Vec<thread::JoinHandle<()>>>();
+
+		if is_realtime {
+			for thread in threads.iter() {
+				if let Err(e) = set_realtime_priority(thread) {
+					println!("Worker threads run at normal priority: {}", e);
+					break;
+				}
+			}
+		}
+
+		WorkerPool {
+			state,
+			threads,
+			epoch: 0
+		}
+	}
+
+	pub(crate) fn num_workers(&self) -> usize {
+		self.threads.len()
+	}
+
+	// Calls task once for every index below len, spread over the workers and the calling thread,
+	// and returns once every call is done. The order of the calls is not defined.
+	pub(crate) fn run(&mut self, len: usize, task: &(dyn Fn(usize) + Sync)){
+		if len == 0 {
+			return;
+		}
+
+		self.epoch = self.epoch.wrapping_add(1);
+
+		// the previous task is done and has nothing left to claim, so no worker reads this right now
+		let task: Task = unsafe { std::mem::transmute::<&(dyn Fn(usize) + Sync), Task>(task) };
+		unsafe { *self.state.task.get() = Some((task, len)) };
+
+		self.state.pending.store(len, Ordering::SeqCst);
+		self.state.claims.store((u64::from(self.epoch) << 32) | len as u64, Ordering::SeqCst);
+
+		if self.state.num_sleeping.load(Ordering::SeqCst) > 0 {
+			for thread in self.threads.iter() {
+				thread.thread().unpark();
+			}
+		}
+
+		self.state.work();
+
+		let spin_until = Instant::now() + SPIN_TIME;
+		while self.state.pending.load(Ordering::Acquire) > 0 {
+			if Instant::now() < spin_until {
+				std::hint::spin_loop();
+			} else {
+				thread::yield_now();
+			}
+		}
+	}
+}
+
+impl Drop for WorkerPool {
+	fn drop(&mut self){
+		self.state.is_stopping.store(true, Ordering::SeqCst);
+
+		for thread in self.threads.drain(..) {
+			thread.thread().unpark();
+			let _ = thread.join();
+		}
+	}
+}
+
+// Moves a thread to the real-time scheduler, so a busy system doesn't hold up the render. This takes
+// the right to, like an rtprio limit on Linux, otherwise the thread stays as it was.
+#[cfg(unix)]
+pub(crate) fn set_realtime_priority<T>(thread: &thread::JoinHandle<T>) -> Result<(), String> {
+	use std::os::unix::thread::JoinHandleExt;
+
+	let param = libc::sched_param { sched_priority: REALTIME_PRIORITY };
+	let result = unsafe { libc::pthread_setschedparam(thread.as_pthread_t(), libc::SCHED_FIFO, &param) };
+
+	if result == 0 {
+		Ok(())
+	} else {
+		Err(format!("couldn't raise the priority of thread {}: {}", thread.thread().name().unwrap_or("unnamed"), std::io::Error::from_raw_os_error(result)))
+	}
+}
+
+// Other platforms have their own ways to get there, which aren't done yet
+#[cfg(not(unix))]
+pub(crate) fn set_realtime_priority<T>(_thread: &thread::JoinHandle<T>) -> Result<(), String> {
+	Err(String::from("real-time priority isn't supported on this platform"))
+}
\ No newline at end of file
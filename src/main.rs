@@ -2,7 +2,8 @@ use std::fs::File;
 use std::io::Write;
 use std::process::Command;
 use std::thread;
-use std::sync::mpsc::{Sender, Receiver};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 
 mod core;
 mod behavior;
@@ -10,9 +11,16 @@ mod websocket;
 use crate::core::node::*;
 use crate::core::node_graph::*;
 use crate::core::audio::*;
-use crate::core::heaped::Heaped;
-use crate::behavior::waveform::*;
+use crate::core::error::GraphError;
+use crate::core::patch::Patch;
+use crate::core::input::*;
+use crate::core::offline::{create_wav_file, render_to_wav, stop_when_silent};
+use crate::core::channel_layout::{ChannelLayout, MAX_CHANNELS};
+use crate::core::wav::WavFormat;
+use crate::core::renderer::{new_shared_output_buffer, Renderer, SharedOutputBuffer};
+use crate::core::workers::set_realtime_priority;
 use crate::behavior::basic::*;
+use crate::behavior::{register_node_recipes, usize_parameter};
 use crate::websocket::message::*;
 use crate::websocket::server::Server;
 
@@ -22,87 +30,569 @@ extern crate ringbuf;
 
 fn write_file(path: &str, data: &String){
 	let mut output = File::create(path).unwrap();
-	write!(output, "{}", data);
+	write!(output, "{}", data).unwrap();
 }
 
 fn run_dot(dot_path: &str, png_path: &str){
-	Command::new("/opt/homebrew/bin/dot").arg("-Tpng").arg(dot_path).arg("-o").arg(png_path).status();
+	if let Err(e) = Command::new("/opt/homebrew/bin/dot").arg("-Tpng").arg(dot_path).arg("-o").arg(png_path).status() {
+		println!("Couldn't run dot: {}", e);
+	}
+}
+
+// Writes the graph in the dot format, and renders it to a png next to it
+fn write_dot(graph: &NodeGraph, dot_path: &str){
+	write_file(dot_path, &graph.to_dot());
+	run_dot(dot_path, &std::path::Path::new(dot_path).with_extension("png").to_string_lossy());
+}
+
+fn save_patch(graph: &NodeGraph, path: &str) -> Result<(), ErrorMessage> {
+	let patch = graph.to_patch()?;
+
+	std::fs::write(path, patch.to_json()).map_err(|e| ErrorMessage { code: String::from("save_failed"), message: format!("Couldn't write the patch to {}: {}", path, e) })
+}
+
+// Applies a message from a websocket client to the graph, returning the reply to send back
+fn apply_client_message(graph: &mut NodeGraph, audio_manager: &mut AudioManager, message: ClientMessage) -> Result<ServerMessage, ErrorMessage> {
+	match message {
+		ClientMessage::AddNode(add_node_message) => {
+			let name = add_node_message.name.unwrap_or_else(|| add_node_message.node_type.clone());
+			let node_id = graph.add_node_by_recipe(&name, &add_node_message.node_type, add_node_message.parameters)?;
+
+			Ok(ServerMessage::NodeAdded(NodeAddedMessage {
+				node_id,
+				node_type: add_node_message.node_type,
+				name
+			}))
+		},
+		ClientMessage::RemoveNode(remove_node_message) => {
+			graph.remove_node(remove_node_message.node_id)?;
+
+			Ok(ServerMessage::Alright(AlrightMessage { message: "remove node ok!".to_string() }))
+		},
+		ClientMessage::ConnectNodes(connect_nodes_message) => {
+			if connect_nodes_message.feedback {
+				graph.connect_feedback(connect_nodes_message.from_id, connect_nodes_message.output_idx, connect_nodes_message.to_id, connect_nodes_message.input_idx)?;
+			} else {
+				graph.connect(connect_nodes_message.from_id, connect_nodes_message.output_idx, connect_nodes_message.to_id, connect_nodes_message.input_idx)?;
+			}
+
+			if let Some(gain) = connect_nodes_message.gain {
+				graph.set_edge_gain(connect_nodes_message.from_id, connect_nodes_message.output_idx, connect_nodes_message.to_id, connect_nodes_message.input_idx, gain)?;
+			}
+
+			Ok(ServerMessage::Alright(AlrightMessage { message: "connect nodes ok!".to_string() }))
+		},
+		ClientMessage::DisconnectNodes(disconnect_nodes_message) => {
+			graph.disconnect(disconnect_nodes_message.from_id, disconnect_nodes_message.output_idx, disconnect_nodes_message.to_id, disconnect_nodes_message.input_idx)?;
+
+			Ok(ServerMessage::Alright(AlrightMessage { message: "disconnect nodes ok!".to_string() }))
+		},
+		ClientMessage::SetParameter(set_parameter_message) => {
+			graph.set_parameter(set_parameter_message.node_id, set_parameter_message.parameter_id, set_parameter_message.value)?;
+
+			Ok(ServerMessage::Alright(AlrightMessage { message: "set parameter ok!".to_string() }))
+		},
+		ClientMessage::GetParameters(get_parameters_message) => {
+			let parameters = graph.get_parameters(get_parameters_message.node_id)?.into_iter().enumerate().map(|(parameter_id, (descriptor, value))| {
+				ParameterState { parameter_id, descriptor, value }
+			}).collect();
+
+			Ok(ServerMessage::Parameters(ParametersMessage {
+				node_id: get_parameters_message.node_id,
+				parameters,
+				creation_parameters: graph.get_creation_parameters(get_parameters_message.node_id)?
+			}))
+		},
+		ClientMessage::GetPatch => {
+			Ok(ServerMessage::Patch(graph.to_patch()?))
+		},
+		ClientMessage::GetAudioConfig => {
+			Ok(ServerMessage::AudioConfig(AudioConfigMessage {
+				config: audio_manager.get_output_config().cloned(),
+				input: audio_manager.get_input_config().cloned(),
+				block_size: graph.get_context().block_size,
+				channel_layout: audio_manager.get_channel_layout()
+			}))
+		},
+		ClientMessage::ListDevices => {
+			Ok(ServerMessage::Devices(DevicesMessage { devices: audio_manager.list_devices() }))
+		},
+		ClientMessage::SelectDevice(select_device_message) => {
+			let config = audio_manager.select_output_device(&select_device_message.host_name, &select_device_message.device_name)?;
+
+			if config.sample_rate as f32 != graph.get_context().sample_rate {
+				graph.set_sample_rate(config.sample_rate as f32)?;
+			}
+
+			Ok(ServerMessage::AudioConfig(AudioConfigMessage {
+				config: Some(config),
+				input: audio_manager.get_input_config().cloned(),
+				block_size: graph.get_context().block_size,
+				channel_layout: audio_manager.get_channel_layout()
+			}))
+		},
+		ClientMessage::SetBlockSize(set_block_size_message) => {
+			graph.set_block_size(set_block_size_message.block_size)?;
+
+			// the graph works with any device buffer size, so it's fine to keep the old stream if this fails
+			if let Err(e) = audio_manager.set_block_size(set_block_size_message.block_size) {
+				println!("Couldn't reopen the output for the new block size: {}", e);
+			}
+
+			Ok(ServerMessage::AudioConfig(AudioConfigMessage {
+				config: audio_manager.get_output_config().cloned(),
+				input: audio_manager.get_input_config().cloned(),
+				block_size: graph.get_context().block_size,
+				channel_layout: audio_manager.get_channel_layout()
+			}))
+		},
+		ClientMessage::SetProfiling(set_profiling_message) => {
+			graph.set_profiling(set_profiling_message.enabled)?;
+
+			Ok(ServerMessage::Alright(AlrightMessage { message: "set profiling ok!".to_string() }))
+		},
+		ClientMessage::GetProfile => {
+			let nodes = graph.get_profile().into_iter().map(|(node_id, profile)| NodeProfileState { node_id, profile }).collect();
+
+			Ok(ServerMessage::Profile(ProfileMessage {
+				enabled: graph.is_profiling(),
+				block_budget_us: graph.get_block_budget_us(),
+				nodes
+			}))
+		}
+	}
+}
+
+// The patch that plays when no patch file is given
+fn build_demo_graph(graph: &mut NodeGraph) -> Result<(), GraphError> {
+	let sin_freq = graph.add_node_by_recipe("sin_freq", "WaveformNode", waveform_parameters(vec![250.0]))?;
+	let sin = graph.add_node_by_recipe("sin", "SinNode", NodeParameters::new())?;
+
+	let sin_offset_amt = graph.add_node_by_recipe("sin_offset_amt", "WaveformNode", waveform_parameters(vec![1.0]))?;
+
+	let sin_2_freq = graph.add_node_by_recipe("sin_2_freq", "WaveformNode", waveform_parameters(vec![300.0]))?;
+	let sin_2_freq_mod = graph.add_node_by_recipe("sin_2_freq_mod", "ProductNode", NodeParameters::new())?;
+
+	let sin_2 = graph.add_node_by_recipe("sin2", "SinNode", NodeParameters::new())?;
+
+	let left_amp_mod_freq = graph.add_node_by_recipe("left_amp_mod_freq", "WaveformNode", waveform_parameters(vec![0.21323]))?;
+	let left_amp_mod = graph.add_node_by_recipe("left_amp_mod", "SinNode", NodeParameters::new())?;
+
+	let right_amp_mod_freq = graph.add_node_by_recipe("right_amp_mod_freq", "WaveformNode", waveform_parameters(vec![0.372819]))?;
+	let right_amp_mod = graph.add_node_by_recipe("right_amp_mod", "SinNode", NodeParameters::new())?;
+
+	let left_amp = graph.add_node_by_recipe("left_amp", "ProductNode", NodeParameters::new())?;
+	let right_amp = graph.add_node_by_recipe("right_amp", "ProductNode", NodeParameters::new())?;
+
+	// a stereo output, mixed onto whatever the channel layout is
+	let output = graph.add_node_by_recipe("output", "InterleavingOutputNode", NodeParameters::new())?;
+
+	graph.connect(sin_freq, 0, sin, 0)?;
+
+	// the frequency modulation is sin*0.5 + 1.0, summed straight into the input
+	graph.connect(sin, 0, sin_2_freq_mod, 0)?;
+	graph.set_edge_gain(sin, 0, sin_2_freq_mod, 0, 0.5)?;
+	graph.connect(sin_offset_amt, 0, sin_2_freq_mod, 0)?;
+
+	graph.connect(sin_2_freq, 0, sin_2_freq_mod, 1)?;
+	graph.connect(sin_2_freq_mod, 0, sin_2, 0)?;
+
+	graph.connect(sin_2, 0, left_amp, 0)?;
+	graph.connect(sin_2, 0, right_amp, 0)?;
+
+	graph.connect(left_amp_mod_freq, 0, left_amp_mod, 0)?;
+	graph.connect(left_amp_mod, 0, left_amp, 1)?;
+
+	graph.connect(right_amp_mod_freq, 0, right_amp_mod, 0)?;
+	graph.connect(right_amp_mod, 0, right_amp, 1)?;
+
+	graph.connect(left_amp, 0, output, 0)?;
+	graph.connect(right_amp, 0, output, 1)?;
+
+	Ok(())
+}
+
+fn waveform_parameters(waveform: Vec<f32>) -> NodeParameters {
+	let mut parameters = NodeParameters::new();
+	parameters.insert(String::from("waveform"), serde_json::json!(waveform));
+
+	parameters
+}
+
+fn load_patch_file(path: &str, context: ProcessContext) -> (NodeGraph, Renderer) {
+	let json = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Couldn't read patch file {}: {}", path, e));
+
+	match Patch::from_json(&json).and_then(|patch| NodeGraph::from_patch(&patch, context)) {
+		Ok(loaded) => loaded,
+		Err(e) => panic!("Couldn't load patch file {}: {}", path, e)
+	}
+}
+
+// Input nodes still get this many channels when nothing is captured
+const SILENT_INPUT_CHANNELS: usize = 2;
+
+// How many blocks the graph thread renders ahead of the output device, which makes the output latency scale with the block size
+const BLOCKS_AHEAD: usize = 16;
+
+// How often the engine stats are sent to websocket clients
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Opens what the input nodes read from: the WAV file if one is given, otherwise the default input device when playing
+fn open_input(audio_manager: Option<&mut AudioManager>, input_path: &Option<String>, sample_rate: u32) -> Option<AudioInput> {
+	match (audio_manager, input_path) {
+		(None, Some(input_path)) => {
+			let file_input = FileInput::open(input_path, sample_rate).unwrap_or_else(|e| panic!("{}", e));
+
+			Some(AudioInput::from_file(file_input))
+		},
+		(Some(audio_manager), Some(input_path)) => {
+			let file_input = FileInput::open(input_path, sample_rate).unwrap_or_else(|e| panic!("{}", e));
+			let channels = file_input.channels();
+
+			let (ringbuf_prod, ringbuf_cons) = ringbuf::RingBuffer::<f32>::new(MAX_BLOCK_SIZE*8*channels).split();
+			audio_manager.open_file_input(input_path, file_input, sample_rate, ringbuf_prod);
+
+			Some(AudioInput::from_stream(ringbuf_cons, channels, audio_manager.get_stats()))
+		},
+		(Some(audio_manager), None) => {
+			let result = audio_manager.negotiate_input_config(sample_rate).and_then(|input_config| {
+				let channels = usize::from(input_config.channels);
+
+				let (ringbuf_prod, ringbuf_cons) = ringbuf::RingBuffer::<f32>::new(MAX_BLOCK_SIZE*8*channels).split();
+				audio_manager.open_input_stream(&input_config, ringbuf_prod)?;
+
+				Ok(AudioInput::from_stream(ringbuf_cons, channels, audio_manager.get_stats()))
+			});
+
+			match result {
+				Ok(audio_input) => Some(audio_input),
+				Err(e) => {
+					println!("Not capturing any input, the input nodes stay silent: {}", e);
+					None
+				}
+			}
+		},
+		(None, None) => None
+	}
+}
+
+const USAGE: &str = "usage:
+	iannis [patch.json] [--input-file <input.wav>] [--block-size <frames>] [--channels <layout>|device] [--threads <n>] [--profile] [--save-patch <patch.json>] [--dot <graph.dot>]
+		play the patch (or the demo graph) on the default output device, controlled over a websocket on port 9001.
+		input nodes read from the default input device, or loop the given WAV file instead
+	iannis render <output.wav> [--patch <patch.json>] [--input-file <input.wav>] [--block-size <frames>] [--channels <layout>] [--seconds <n>] [--sample-rate <hz>] [--format int16|int24|float32] [--until-silent] [--threads <n>] [--profile] [--save-patch <patch.json>] [--dot <graph.dot>]
+		render the patch (or the demo graph) to a WAV file as fast as possible, without an audio device
+
+	the block size is between 32 and 2048 frames, 256 by default.
+	the channel layout is mono, stereo, quad, 5.1, 7.1 or a number of channels, stereo by default.
+	when playing, device takes as many channels as the output device has.
+	the graph is rendered on as many threads as there are cores by default, or one less when playing to leave one to the audio device.
+	1 renders it on a single thread. When playing, the render threads ask for real-time priority.
+	--save-patch saves the patch (or the demo graph) as it was loaded, clients can get it later on with a GetPatch message.
+	--dot writes the graph in the dot format, and renders it to a png next to it.
+	--profile times every node, which shows in the dot file after rendering (graph.dot unless given), or can be asked for over the websocket";
+
+enum Mode {
+	// no channel layout means the one of the output device
+	Realtime { patch_path: Option<String>, input_path: Option<String>, block_size: usize, channel_layout: Option<ChannelLayout>, num_threads: usize, profile: bool, save_patch_path: Option<String>, dot_path: Option<String> },
+	Render(RenderOptions)
+}
+
+struct RenderOptions {
+	output_path: String,
+	patch_path: Option<String>,
+	input_path: Option<String>,
+	block_size: usize,
+	channel_layout: ChannelLayout,
+	seconds: f32,
+	sample_rate: u32,
+	format: WavFormat,
+	until_silent: bool,
+	num_threads: usize,
+	profile: bool,
+	save_patch_path: Option<String>,
+	dot_path: Option<String>
+}
+
+fn parse_block_size(value: &str) -> Result<usize, String> {
+	let block_size: usize = value.parse().map_err(|e| format!("Invalid block size: {}", e))?;
+
+	if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+		return Err(format!("The block size has to be between {} and {} frames", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+	}
+
+	Ok(block_size)
+}
+
+fn parse_num_threads(value: &str) -> Result<usize, String> {
+	match value.parse() {
+		Ok(num_threads) if num_threads > 0 => Ok(num_threads),
+		_ => Err(format!("Invalid number of threads '{}'", value))
+	}
+}
+
+// Every core when rendering offline. While playing, one core is left to the audio callback,
+// which the render threads at real-time priority would otherwise compete with.
+fn default_num_threads(is_realtime: bool) -> usize {
+	let num_cores = thread::available_parallelism().map_or(1, |num_cores| num_cores.get());
+
+	if is_realtime { (num_cores - 1).max(1) } else { num_cores }
+}
+
+fn parse_args(args: &[String]) -> Result<Mode, String> {
+	if args.first().map(String::as_str) != Some("render") {
+		let mut patch_path = None;
+		let mut input_path = None;
+		let mut block_size = DEFAULT_BLOCK_SIZE;
+		let mut channel_layout = Some(ChannelLayout::default());
+		let mut num_threads = default_num_threads(true);
+		let mut profile = false;
+		let mut save_patch_path = None;
+		let mut dot_path = None;
+		let mut remaining = args.iter();
+
+		while let Some(arg) = remaining.next() {
+			let mut value = |flag: &str| remaining.next().cloned().ok_or(format!("Missing value for {}", flag));
+
+			match arg.as_str() {
+				"--input-file" => input_path = Some(value(arg)?),
+				"--block-size" => block_size = parse_block_size(&value(arg)?)?,
+				"--channels" => {
+					channel_layout = match value(arg)?.as_str() {
+						"device" => None,
+						layout => Some(layout.parse()?)
+					};
+				},
+				"--threads" => num_threads = parse_num_threads(&value(arg)?)?,
+				"--profile" => profile = true,
+				"--save-patch" => save_patch_path = Some(value(arg)?),
+				"--dot" => dot_path = Some(value(arg)?),
+				_ if patch_path.is_none() && !arg.starts_with("--") => patch_path = Some(arg.clone()),
+				_ => return Err(format!("Unexpected argument '{}'", arg))
+			}
+		}
+
+		return Ok(Mode::Realtime { patch_path, input_path, block_size, channel_layout, num_threads, profile, save_patch_path, dot_path });
+	}
+
+	let mut options = RenderOptions {
+		output_path: String::new(),
+		patch_path: None,
+		input_path: None,
+		block_size: DEFAULT_BLOCK_SIZE,
+		channel_layout: ChannelLayout::default(),
+		seconds: 10.0,
+		sample_rate: DEFAULT_SAMPLE_RATE as u32,
+		format: WavFormat::Int24,
+		until_silent: false,
+		num_threads: default_num_threads(false),
+		profile: false,
+		save_patch_path: None,
+		dot_path: None
+	};
+
+	let mut output_path = None;
+	let mut remaining = args[1..].iter();
+
+	while let Some(arg) = remaining.next() {
+		let mut value = |flag: &str| remaining.next().cloned().ok_or(format!("Missing value for {}", flag));
+
+		match arg.as_str() {
+			"--patch" => options.patch_path = Some(value(arg)?),
+			"--input-file" => options.input_path = Some(value(arg)?),
+			"--block-size" => options.block_size = parse_block_size(&value(arg)?)?,
+			"--channels" => options.channel_layout = value(arg)?.parse()?,
+			"--seconds" => {
+				options.seconds = value(arg)?.parse().map_err(|e| format!("Invalid number of seconds: {}", e))?;
+			},
+			"--sample-rate" => {
+				options.sample_rate = value(arg)?.parse().map_err(|e| format!("Invalid sample rate: {}", e))?;
+
+				if options.sample_rate == 0 {
+					return Err(String::from("The sample rate has to be above 0"));
+				}
+			},
+			"--format" => {
+				options.format = match value(arg)?.as_str() {
+					"int16" => WavFormat::Int16,
+					"int24" => WavFormat::Int24,
+					"float32" => WavFormat::Float32,
+					other => return Err(format!("Unknown WAV format '{}'", other))
+				};
+			},
+			"--until-silent" => options.until_silent = true,
+			"--threads" => options.num_threads = parse_num_threads(&value(arg)?)?,
+			"--profile" => options.profile = true,
+			"--save-patch" => options.save_patch_path = Some(value(arg)?),
+			"--dot" => options.dot_path = Some(value(arg)?),
+			_ if output_path.is_none() && !arg.starts_with("--") => output_path = Some(arg.clone()),
+			_ => return Err(format!("Unexpected argument '{}'", arg))
+		}
+	}
+
+	options.output_path = output_path.ok_or("Missing the path of the WAV file to render to")?;
+
+	Ok(Mode::Render(options))
+}
+
+fn render_offline(renderer: &mut Renderer, output_buffer: &SharedOutputBuffer, mut audio_input: Option<&mut AudioInput>, options: &RenderOptions){
+	println!("Rendering {} seconds at {} Hz in {} to {}", options.seconds, options.sample_rate, options.channel_layout, options.output_path);
+
+	let render_block = || {
+		let num_frames = renderer.get_context().block_size;
+
+		if let Some(audio_input) = audio_input.as_mut() {
+			audio_input.pull_block(num_frames);
+		}
+
+		renderer.update();
+
+		num_frames
+	};
+
+	let channels = options.channel_layout.channels();
+
+	let path = std::path::Path::new(&options.output_path);
+	let result = create_wav_file(path, options.format, channels as u16, options.sample_rate).and_then(|writer| {
+		if options.until_silent {
+			render_to_wav(render_block, output_buffer, writer, options.seconds, stop_when_silent(0.0001, 1.0, options.sample_rate, channels))
+		} else {
+			render_to_wav(render_block, output_buffer, writer, options.seconds, |_| false)
+		}
+	});
+
+	match result {
+		Ok(num_frames) => println!("Rendered {} frames ({} seconds)", num_frames, num_frames as f32 / options.sample_rate as f32),
+		Err(e) => {
+			eprintln!("Couldn't render to {}: {}", options.output_path, e);
+			std::process::exit(1);
+		}
+	}
 }
 
 fn main() {
-	let ringbuf_buffer_size = (BUFFER_SIZE*32).try_into().expect("Ringbuf BUFFER_SIZE*32 cannot fit into usize!");
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let mode = match parse_args(&args) {
+		Ok(mode) => mode,
+		Err(e) => {
+			eprintln!("{}\n\n{}", e, USAGE);
+			std::process::exit(2);
+		}
+	};
 
-	let mut ringbuf = ringbuf::RingBuffer::<f32>::new(ringbuf_buffer_size);
-	let (mut ringbuf_prod, mut ringbuf_cons) = ringbuf.split();
+	register_node_recipes();
 
-	let mut audio_manager = AudioManager::new();
+	// the device decides the sample rate when playing, so it has to be opened before the graph is built
+	let (patch_path, input_path, sample_rate, block_size, channel_layout, num_threads, profile, save_patch_path, dot_path, mut audio) = match &mode {
+		Mode::Realtime { patch_path, input_path, block_size, channel_layout, num_threads, profile, save_patch_path, dot_path } => {
+			let audio_manager = AudioManager::new(*block_size, *channel_layout);
+			let output_config = audio_manager.negotiate_output_config(None).unwrap_or_else(|e| panic!("{}", e));
 
-	let (ws_out_tx, ws_out_rx): (Sender<ClientMessage>, Receiver<ClientMessage>) = channel();
-	let (ws_in_tx, ws_in_rx): (Sender<ServerMessage>, Receiver<ServerMessage>) = channel();
+			(patch_path, input_path, output_config.sample_rate, *block_size, audio_manager.get_channel_layout(), *num_threads, *profile, save_patch_path, dot_path, Some((audio_manager, output_config)))
+		},
+		Mode::Render(options) => {
+			(&options.patch_path, &options.input_path, options.sample_rate, options.block_size, options.channel_layout, options.num_threads, options.profile, &options.save_patch_path, &options.dot_path, None)
+		}
+	};
 
-	let graph_thread = thread::spawn(move || {
-		let mut graph = NodeGraph::new();
+	println!("Running at {} Hz in {}, with blocks of {} frames", sample_rate, channel_layout, block_size);
 
-		let sin_freq = graph.add_node("sin_freq", Box::new(WaveformNode::new(vec![250.0])));
-		let sin = graph.add_node("sin", Box::new(SinNode::new()));
+	// output nodes have an input for every channel of the layout by default, the old stereo output node is mixed onto it
+	let channels = channel_layout.channels();
+	let output_buffer = new_shared_output_buffer(channels);
 
-		let sin_scale_amt = graph.add_node("sin_scale_amt", Box::new(WaveformNode::new(vec![0.5])));
-		let sin_scale = graph.add_node("sin_scale", Box::new(ProductNode::new(2)));
+	let recipe_output_buffer = output_buffer.clone();
+	register_node_recipe("OutputNode", Box::new(move |parameters| {
+		let num_ins = usize_parameter(parameters, "num_ins", channels, usize::from(MAX_CHANNELS))?;
 
-		let sin_offset_amt = graph.add_node("sin_offset_amt", Box::new(WaveformNode::new(vec![1.0])));
-		let sin_offset = graph.add_node("sin_offset", Box::new(SumNode::new(2)));
+		if num_ins == 0 {
+			return Err(GraphError::InvalidParameter { name: String::from("num_ins"), reason: String::from("an output node needs at least one input") });
+		}
 
-		let sin_2_freq = graph.add_node("sin_2_freq", Box::new(WaveformNode::new(vec![300.0])));
-		let sin_2_freq_mod = graph.add_node("sin_2_freq_mod", Box::new(ProductNode::new(2)));
+		Ok(Box::new(OutputNode::new(recipe_output_buffer.clone(), num_ins, channels)))
+	}));
 
-		let sin_2 = graph.add_node("sin2", Box::new(SinNode::new()));
+	let recipe_output_buffer = output_buffer.clone();
+	register_node_recipe("InterleavingOutputNode", Box::new(move |_| Ok(Box::new(OutputNode::new(recipe_output_buffer.clone(), 2, channels)))));
 
-		let left_amp_mod_freq = graph.add_node("left_amp_mod_freq", Box::new(WaveformNode::new(vec![0.21323])));
-		let left_amp_mod = graph.add_node("left_amp_mod", Box::new(SinNode::new()));
+	// the input decides how many outputs the input nodes have, so the same goes for it
+	let mut audio_input = open_input(audio.as_mut().map(|(audio_manager, _)| audio_manager), input_path, sample_rate);
+	let (input_buffer, input_channels) = match &audio_input {
+		Some(audio_input) => (audio_input.get_buffer(), audio_input.channels()),
+		None => (new_shared_input_buffer(SILENT_INPUT_CHANNELS), SILENT_INPUT_CHANNELS)
+	};
+	register_node_recipe("AudioInputNode", Box::new(move |_| Ok(Box::new(AudioInputNode::new(input_buffer.clone(), input_channels)))));
+	let context = ProcessContext::new(sample_rate as f32, block_size);
 
-		let right_amp_mod_freq = graph.add_node("right_amp_mod_freq", Box::new(WaveformNode::new(vec![0.372819])));
-		let right_amp_mod = graph.add_node("right_amp_mod", Box::new(SinNode::new()));
+	let (mut graph, mut renderer) = match patch_path {
+		Some(patch_path) => load_patch_file(patch_path, context),
+		None => {
+			let (mut graph, renderer) = NodeGraph::new(context);
+			build_demo_graph(&mut graph).unwrap();
 
-		let left_amp = graph.add_node("left_amp", Box::new(ProductNode::new(2)));
-		let right_amp = graph.add_node("right_amp", Box::new(ProductNode::new(2)));
+			(graph, renderer)
+		}
+	};
 
-		let output_buffer: Heaped<Vec<f32>> = Heaped::new_with_value(vec![0.0; BUFFER_SIZE*2]);
-		let output = graph.add_node("output", Box::new(InterleavingOutputNode::new(output_buffer)));
+	if let Some(save_patch_path) = save_patch_path {
+		if let Err(e) = save_patch(&graph, save_patch_path) {
+			println!("Couldn't save the graph as a patch: {}", e.message);
+		}
+	}
 
-		graph.connect(sin_freq, 0, sin, 0);
-		graph.connect(sin, 0, sin_scale, 0);
-		graph.connect(sin_scale_amt, 0, sin_scale, 1);
+	if let Some(dot_path) = dot_path {
+		write_dot(&graph, dot_path);
+	}
 
-		graph.connect(sin_scale, 0, sin_offset, 0);
-		graph.connect(sin_offset_amt, 0, sin_offset, 1);
+	graph.commit().unwrap();
 
-		graph.connect(sin_offset, 0, sin_2_freq_mod, 0);
-		graph.connect(sin_2_freq, 0, sin_2_freq_mod, 1);
-		graph.connect(sin_2_freq_mod, 0, sin_2, 0);
+	renderer.set_output_buffer(output_buffer.clone(), channels);
+	renderer.set_num_threads(num_threads, matches!(mode, Mode::Realtime { .. }));
+	println!("Rendering the graph on {} thread(s)", renderer.get_num_threads());
 
-		graph.connect(sin_2, 0, left_amp, 0);
-		graph.connect(sin_2, 0, right_amp, 0);
+	if profile {
+		graph.set_profiling(true).unwrap();
+	}
 
-		graph.connect(left_amp_mod_freq, 0, left_amp_mod, 0);
-		graph.connect(left_amp_mod, 0, left_amp, 1);
+	if let Mode::Render(options) = &mode {
+		render_offline(&mut renderer, &output_buffer, audio_input.as_mut(), options);
 
-		graph.connect(right_amp_mod_freq, 0, right_amp_mod, 0);
-		graph.connect(right_amp_mod, 0, right_amp, 1);
+		// the graph again, with the timings of every node
+		if profile {
+			write_dot(&graph, dot_path.as_deref().unwrap_or("graph.dot"));
+		}
 
-		graph.connect(left_amp, 0, output, 0);
-		graph.connect(right_amp, 0, output, 1);
+		return;
+	}
+
+	let (mut audio_manager, output_config) = audio.expect("The audio manager should have been created for playing!");
+
+	let ringbuf_buffer_size = MAX_BLOCK_SIZE*channels*BLOCKS_AHEAD;
+
+	let ringbuf = ringbuf::RingBuffer::<f32>::new(ringbuf_buffer_size);
+	let (mut ringbuf_prod, ringbuf_cons) = ringbuf.split();
 
-		write_file("graph.dot", &graph.to_dot());
-		run_dot("graph.dot", "graph.png");
+	let (ws_out_tx, ws_out_rx): (Sender<ClientMessage>, Receiver<ClientMessage>) = channel();
+	let (ws_in_tx, ws_in_rx): (Sender<ServerMessage>, Receiver<ServerMessage>) = channel();
 
+	let stats = audio_manager.get_stats();
+	let render_stats = stats.clone();
+
+	let graph_thread = thread::spawn(move || {
 		loop {
-			let remaining = ringbuf_prod.remaining();
-			if remaining > BUFFER_SIZE {
-				graph.update();
+			let block_size = renderer.get_context().block_size;
+			if ringbuf_prod.len() + block_size*channels <= block_size*channels*BLOCKS_AHEAD {
+				let started = Instant::now();
 
-				unsafe {
-					ringbuf_prod.push_slice(&(*output_buffer.const_ptr));
+				if let Some(audio_input) = audio_input.as_mut() {
+					audio_input.pull_block(block_size);
 				}
+
+				renderer.update();
+
+				render_stats.record_render(started.elapsed(), block_size);
+
+				ringbuf_prod.push_slice(&output_buffer.lock().unwrap());
 				//println!(".");
 			} else {
 				//println!("parking");
@@ -111,33 +601,54 @@ fn main() {
 		}
 	});
 
-	audio_manager.open_output_stream(ringbuf_cons, graph_thread.thread().clone());
+	// the workers wait on the graph thread at the end of every level, so it gets the same priority
+	if let Err(e) = set_realtime_priority(&graph_thread) {
+		println!("The graph thread runs at normal priority: {}", e);
+	}
 
-	let mut websocket_server: Server = Server::new();
+	if let Err(e) = audio_manager.open_output_stream(&output_config, ringbuf_cons, graph_thread.thread().clone()) {
+		panic!("{}", e);
+	}
+
+	let websocket_server: Server = Server::new();
 	websocket_server.run(9001, ws_out_tx, ws_in_rx);
 
+	let mut next_stats = Instant::now() + STATS_INTERVAL;
+
 	loop {
-		let maybe_ws_msg = ws_out_rx.recv();
+		// wake up now and then even without messages, so a commit that couldn't go through is retried
+		// and the plans the renderer is done with get dropped
+		match ws_out_rx.recv_timeout(Duration::from_millis(100)) {
+			Ok(message_from_ws) => {
+				println!("Got message from client: {:?}", message_from_ws);
+
+				let reply = match apply_client_message(&mut graph, &mut audio_manager, message_from_ws) {
+					Ok(server_message) => server_message,
+					Err(e) => {
+						println!("Failed to apply client message: {}", e.message);
+						ServerMessage::Error(e)
+					}
+				};
+
+				ws_in_tx.send(reply).unwrap();
+			},
+			Err(RecvTimeoutError::Timeout) => {},
+			Err(RecvTimeoutError::Disconnected) => break
+		}
 
-		if let Ok(message_from_ws) = maybe_ws_msg {
-			match message_from_ws {
-				ClientMessage::AddNode(add_node_message) => {
-					println!("Got add node message from client!");
-					ws_in_tx.send(ServerMessage::Alright(AlrightMessage { message: "add node ok!".to_string() }));
-				},
-				ClientMessage::RemoveNode(remove_node_message) => {
-					println!("Got remove node message from client!");
-					ws_in_tx.send(ServerMessage::Alright(AlrightMessage { message: "remove node ok!".to_string() }));
-				},
-				ClientMessage::ConnectNodes(connect_nodes_message) => {
-					println!("Got connect nodes message");
-					ws_in_tx.send(ServerMessage::Alright(AlrightMessage { message: "connect nodes ok!".to_string() }));
-				},
-				ClientMessage::DisconnectNodes(disconnect_nodes_message) => {
-					println!("Got disconnect nodes message!");
-					ws_in_tx.send(ServerMessage::Alright(AlrightMessage { message: "disconnect nodes ok!".to_string() }));
-				}
+		if let Err(e) = graph.commit() {
+			println!("Couldn't commit graph changes to the renderer: {}", e);
+		}
+
+		if Instant::now() >= next_stats {
+			let snapshot = stats.take_snapshot(graph.get_context().sample_rate as u32);
+
+			if snapshot.render_load > 1.0 {
+				println!("The graph takes longer to render than it plays, {:.0}% of real time", snapshot.render_load * 100.0);
 			}
+
+			ws_in_tx.send(ServerMessage::EngineStats(snapshot)).unwrap();
+			next_stats += STATS_INTERVAL;
 		}
 	}
-}
+}
\ No newline at end of file
@@ -1,6 +1,13 @@
 extern crate serde;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use crate::core::node::{NodeId, NodeParameters};
+use crate::core::parameter::{ParameterDescriptor, ParameterId, ParameterValue};
+use crate::core::patch::Patch;
+use crate::core::audio::{DeviceInfo, InputConfig, OutputConfig};
+use crate::core::channel_layout::ChannelLayout;
+use crate::core::stats::EngineStatsSnapshot;
+use crate::core::profile::NodeProfile;
+use crate::core::error::{AudioError, GraphError};
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", content = "data")]
@@ -9,43 +16,176 @@ pub enum ClientMessage {
 	RemoveNode(RemoveNodeMessage),
 
 	ConnectNodes(ConnectNodesMessage),
-	DisconnectNodes(DisconnectNodesMessage)
+	DisconnectNodes(DisconnectNodesMessage),
+
+	SetParameter(SetParameterMessage),
+	GetParameters(GetParametersMessage),
+
+	GetPatch,
+	GetAudioConfig,
+
+	ListDevices,
+	SelectDevice(SelectDeviceMessage),
+	SetBlockSize(SetBlockSizeMessage),
+
+	SetProfiling(SetProfilingMessage),
+	GetProfile
 }
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(tag = "type", content = "data")]
 pub enum ServerMessage {
-	Alright(AlrightMessage)
+	Alright(AlrightMessage),
+	Error(ErrorMessage),
+	NodeAdded(NodeAddedMessage),
+	Parameters(ParametersMessage),
+	Patch(Patch),
+	EngineStats(EngineStatsSnapshot),
+	AudioConfig(AudioConfigMessage),
+	Devices(DevicesMessage),
+	Profile(ProfileMessage)
 	//GraphStatus(GraphStatusMessage)
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AddNodeMessage {
-	node_type: String
+	pub node_type: String,
+	#[serde(default)]
+	pub name: Option<String>,
+	#[serde(default)]
+	pub parameters: NodeParameters
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RemoveNodeMessage {
-	node_id: usize
+	pub node_id: NodeId
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ConnectNodesMessage {
-	from_id: usize,
-	to_id: usize,
-	output_idx: usize,
-	input_idx: usize
+	pub from_id: NodeId,
+	pub to_id: NodeId,
+	pub output_idx: usize,
+	pub input_idx: usize,
+	// connect through a one block delay, which is needed to close a loop
+	#[serde(default)]
+	pub feedback: bool,
+	// scales the signal before it is summed with the other edges going into the same input
+	#[serde(default)]
+	pub gain: Option<f32>
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DisconnectNodesMessage {
-	from_id: usize,
-	to_id: usize,
-	output_idx: usize,
-	input_idx: usize
+	pub from_id: NodeId,
+	pub to_id: NodeId,
+	pub output_idx: usize,
+	pub input_idx: usize
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SetParameterMessage {
+	pub node_id: NodeId,
+	pub parameter_id: ParameterId,
+	pub value: ParameterValue
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GetParametersMessage {
+	pub node_id: NodeId
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SelectDeviceMessage {
+	pub host_name: String,
+	pub device_name: String
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SetBlockSizeMessage {
+	pub block_size: usize
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SetProfilingMessage {
+	pub enabled: bool
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AlrightMessage {
 	pub message: String
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorMessage {
+	pub code: String,
+	pub message: String
+}
+
+impl From<GraphError> for ErrorMessage {
+	fn from(e: GraphError) -> ErrorMessage {
+		ErrorMessage { code: e.code().to_string(), message: e.to_string() }
+	}
+}
+
+impl From<AudioError> for ErrorMessage {
+	fn from(e: AudioError) -> ErrorMessage {
+		ErrorMessage { code: e.code().to_string(), message: e.to_string() }
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NodeAddedMessage {
+	pub node_id: NodeId,
+	pub node_type: String,
+	pub name: String
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ParametersMessage {
+	pub node_id: NodeId,
+	pub parameters: Vec<ParameterState>,
+	// what the node was created with, like the waveform of a WaveformNode or the inputs of a SumNode.
+	// These are read only, changing them takes a new node.
+	pub creation_parameters: NodeParameters
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ParameterState {
+	pub parameter_id: ParameterId,
+	#[serde(flatten)]
+	pub descriptor: ParameterDescriptor,
+	pub value: ParameterValue
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AudioConfigMessage {
+	// none while no output stream is open
+	pub config: Option<OutputConfig>,
+	// none when nothing is captured, and the input nodes stay silent
+	pub input: Option<InputConfig>,
+	// frames the graph renders at a time
+	pub block_size: usize,
+	// the channels output nodes have inputs for
+	pub channel_layout: ChannelLayout
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DevicesMessage {
+	pub devices: Vec<DeviceInfo>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProfileMessage {
+	pub enabled: bool,
+	// how long a block lasts, the time all nodes share to render it
+	pub block_budget_us: f32,
+	pub nodes: Vec<NodeProfileState>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NodeProfileState {
+	pub node_id: NodeId,
+	#[serde(flatten)]
+	pub profile: NodeProfile
 }
\ No newline at end of file
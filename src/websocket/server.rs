@@ -1,11 +1,10 @@
-use simple_websockets::{Event, Responder};
+use simple_websockets::Event;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::sync::mpsc::{Sender, Receiver};
 use std::thread;
 
 extern crate serde;
-use serde::{Deserialize, Serialize};
 use serde_json;
 
 use crate::websocket::message::*;
@@ -47,7 +46,14 @@ impl Server {
 									channel_out.send(client_message).unwrap();
 								},
 								Err(e) => {
-									println!("Error when attempting to parse incoming websocket message");
+									println!("Error when attempting to parse incoming websocket message: {}", e);
+
+									// the graph never sees the message, so the error goes straight back to whoever sent it
+									let error = ServerMessage::Error(ErrorMessage { code: String::from("invalid_message"), message: format!("Couldn't parse the message: {}", e) });
+
+									if let (Ok(serialized_string), Some(responder)) = (serde_json::to_string(&error), clients.lock().unwrap().get(&client_id)) {
+										responder.send(simple_websockets::Message::Text(serialized_string));
+									}
 								}
 							}
 
@@ -69,17 +75,17 @@ impl Server {
 							Ok(serialized_string) => {
 								let mut unlocked_clients = clients_clone.lock().unwrap();
 
-								for (client_id, responder) in unlocked_clients.iter_mut() {
+								for responder in unlocked_clients.values_mut() {
 									responder.send(simple_websockets::Message::Text(serialized_string.clone()));
 								}
 							},
 							Err(e) => {
-								println!("Error when serialize server message");
+								println!("Error when serialize server message: {}", e);
 							}
 						}
 					},
 					Err(e) => {
-						println!("Error when trying to receive server message from channel");
+						println!("Error when trying to receive server message from channel: {}", e);
 					}
 				}
 			}